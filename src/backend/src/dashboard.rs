@@ -1,18 +1,53 @@
 use askama::Template;
+use std::time::Duration;
 
-use crate::state::State;
+use crate::state::{State, UpgradeRecord};
+
+/// The number of most recent upgrades that are shown on the dashboard.
+const MAX_DISPLAYED_UPGRADES: usize = 10;
 
 #[derive(Template)]
 #[template(path = "dashboard.html")]
 #[derive(Clone)]
 pub struct DashboardTemplate {
     pub greeting: String,
+    pub admins: Vec<String>,
+    pub log_capacity: u64,
+    pub max_logs_body_size: u64,
+    pub max_name_length: u64,
+    pub max_log_message_size: u64,
+    pub low_cycles_threshold_seconds: u64,
+    /// The estimated time in seconds until the freezing threshold is reached.
+    pub cycles_runway_seconds: Option<u64>,
+    /// The most recent upgrades, newest first.
+    pub upgrades: Vec<UpgradeRecord>,
 }
 
 impl DashboardTemplate {
-    pub fn from_state(state: &State) -> Self {
+    pub fn from_state(state: &State, cycles_runway: Option<Duration>) -> Self {
         DashboardTemplate {
             greeting: state.greeting.clone(),
+            admins: state.admins.iter().map(ToString::to_string).collect(),
+            log_capacity: state.log_capacity,
+            max_logs_body_size: state.max_logs_body_size,
+            max_name_length: state.max_name_length,
+            max_log_message_size: state.max_log_message_size,
+            low_cycles_threshold_seconds: state.low_cycles_threshold_seconds,
+            cycles_runway_seconds: cycles_runway.map(|runway| runway.as_secs()),
+            upgrades: state
+                .upgrade_history
+                .iter()
+                .rev()
+                .take(MAX_DISPLAYED_UPGRADES)
+                .cloned()
+                .collect(),
         }
     }
+
+    /// Whether the freezing threshold is estimated to be reached sooner than
+    /// the configured threshold.
+    pub fn is_low_on_cycles(&self) -> bool {
+        self.cycles_runway_seconds
+            .is_some_and(|runway| runway < self.low_cycles_threshold_seconds)
+    }
 }
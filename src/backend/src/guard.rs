@@ -0,0 +1,65 @@
+use crate::state::{is_state_initialized, read_state};
+
+/// Guard that rejects calls from principals that are not controllers of the canister.
+pub fn caller_is_controller() -> Result<(), String> {
+    if ic_cdk::api::is_controller(&ic_cdk::caller()) {
+        Ok(())
+    } else {
+        Err("caller is not a controller".to_string())
+    }
+}
+
+/// Guard that rejects query calls executed in replicated mode, i.e. as an
+/// update call, to avoid expensive queries going through consensus.
+pub fn not_in_replicated_execution() -> Result<(), String> {
+    if ic_cdk::api::in_replicated_execution() {
+        Err("update call rejected".to_string())
+    } else {
+        Ok(())
+    }
+}
+
+/// Guard that rejects calls while a stable memory migration is in progress,
+/// as the state is only initialized once all migrations are completed.
+pub fn state_is_initialized() -> Result<(), String> {
+    if is_state_initialized() {
+        Ok(())
+    } else {
+        Err("stable memory migration in progress, try again later".to_string())
+    }
+}
+
+/// Guard that rejects calls from principals that are neither controllers of
+/// the canister nor configured as admins.
+pub fn caller_is_admin() -> Result<(), String> {
+    state_is_initialized()?;
+    let caller = ic_cdk::caller();
+    if ic_cdk::api::is_controller(&caller) || read_state(|s| s.admins.contains(&caller)) {
+        Ok(())
+    } else {
+        Err("caller is not an admin".to_string())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::guard::state_is_initialized;
+    use crate::lifecycle::InitArg;
+    use crate::state::{initialize_state, State};
+
+    #[test]
+    fn should_reject_calls_until_state_is_initialized() {
+        assert_eq!(
+            state_is_initialized(),
+            Err("stable memory migration in progress, try again later".to_string())
+        );
+        initialize_state(
+            State::try_from(InitArg {
+                greeting: "Hello".to_string(),
+                ..Default::default()
+            })
+            .unwrap(),
+        );
+        assert_eq!(state_is_initialized(), Ok(()));
+    }
+}
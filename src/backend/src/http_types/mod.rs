@@ -5,6 +5,7 @@ mod tests;
 
 use candid::{CandidType, Deserialize};
 use serde_bytes::ByteBuf;
+use std::borrow::Cow;
 
 #[derive(Clone, Debug, CandidType, Deserialize)]
 pub struct HttpRequest {
@@ -24,22 +25,23 @@ impl HttpRequest {
 
     /// Searches for the first appearance of a parameter in the request URL.
     /// Returns `None` if the given parameter does not appear in the query.
-    pub fn raw_query_param(&self, param: &str) -> Option<&str> {
-        const QUERY_SEPARATOR: &str = "?";
-        let query_string = self.url.split(QUERY_SEPARATOR).nth(1)?;
-        if query_string.is_empty() {
-            return None;
-        }
-        const PARAMETER_SEPARATOR: &str = "&";
-        for chunk in query_string.split(PARAMETER_SEPARATOR) {
-            const KEY_VALUE_SEPARATOR: &str = "=";
-            let mut split = chunk.splitn(2, KEY_VALUE_SEPARATOR);
-            let name = split.next()?;
-            if name == param {
-                return Some(split.next().unwrap_or_default());
-            }
-        }
-        None
+    /// The value is percent-decoded like in [`Self::raw_query_params`].
+    pub fn raw_query_param(&self, param: &str) -> Option<Cow<'_, str>> {
+        self.raw_query_params()
+            .find(|(name, _)| name == param)
+            .map(|(_, value)| value)
+    }
+
+    /// Returns all parameters of the request URL in order of appearance, with
+    /// names and values decoded as `application/x-www-form-urlencoded`, i.e.
+    /// percent-encoded bytes are decoded and `+` stands for a space.
+    pub fn raw_query_params(&self) -> impl Iterator<Item = (Cow<'_, str>, Cow<'_, str>)> {
+        let query_string = self
+            .url
+            .split_once('?')
+            .map(|(_, query_string)| query_string)
+            .unwrap_or_default();
+        form_urlencoded::parse(query_string.as_bytes())
     }
 }
 
@@ -77,6 +79,14 @@ impl HttpResponseBuilder {
         })
     }
 
+    pub fn service_unavailable(reason: impl ToString) -> Self {
+        Self(HttpResponse {
+            status_code: 503,
+            headers: vec![],
+            body: ByteBuf::from(reason.to_string()),
+        })
+    }
+
     pub fn server_error(reason: impl ToString) -> Self {
         Self(HttpResponse {
             status_code: 500,
@@ -103,4 +113,4 @@ impl HttpResponseBuilder {
     pub fn build(self) -> HttpResponse {
         self.0
     }
-}
\ No newline at end of file
+}
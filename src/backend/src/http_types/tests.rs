@@ -1,20 +1,61 @@
 use crate::http_types::HttpRequest;
+use crate::logs::LogFilter;
+use std::borrow::Cow;
+
+fn request_with_url(url: String) -> HttpRequest {
+    HttpRequest {
+        method: "".to_string(),
+        url,
+        headers: vec![],
+        body: Default::default(),
+    }
+}
 
 #[test]
 fn test_raw_query_param() {
-    fn request_with_url(url: String) -> HttpRequest {
-        HttpRequest {
-            method: "".to_string(),
-            url,
-            headers: vec![],
-            body: Default::default(),
-        }
-    }
     let http_request = request_with_url("/endpoint?time=1000".to_string());
-    assert_eq!(http_request.raw_query_param("time"), Some("1000"));
+    assert_eq!(
+        http_request.raw_query_param("time").as_deref(),
+        Some("1000")
+    );
     let http_request = request_with_url("/endpoint".to_string());
     assert_eq!(http_request.raw_query_param("time"), None);
     let http_request =
         request_with_url("/endpoint?time=1000&time=1001&other=abcde&time=1002".to_string());
-    assert_eq!(http_request.raw_query_param("time"), Some("1000"));
-}
\ No newline at end of file
+    assert_eq!(
+        http_request.raw_query_param("time").as_deref(),
+        Some("1000")
+    );
+}
+
+#[test]
+fn test_raw_query_params() {
+    let http_request = request_with_url("/endpoint?time=1000&field.caller=abc&flag".to_string());
+    assert_eq!(
+        http_request.raw_query_params().collect::<Vec<_>>(),
+        vec![
+            (Cow::from("time"), Cow::from("1000")),
+            (Cow::from("field.caller"), Cow::from("abc")),
+            (Cow::from("flag"), Cow::from(""))
+        ]
+    );
+}
+
+#[test]
+fn test_decode_query_params() {
+    let http_request = request_with_url(
+        "/logs?contains=hello%2C+%22world%22%26more&field.user%20id=a%3Db&format=text".to_string(),
+    );
+    assert_eq!(
+        http_request.raw_query_param("format").as_deref(),
+        Some("text")
+    );
+    assert_eq!(
+        LogFilter::from_query_params(http_request.raw_query_params()).unwrap(),
+        LogFilter {
+            contains: Some("hello, \"world\"&more".to_string()),
+            fields: vec![("user id".to_string(), "a=b".to_string())],
+            ..Default::default()
+        }
+    );
+}
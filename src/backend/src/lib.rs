@@ -1,4 +1,5 @@
 pub mod dashboard;
+pub mod guard;
 pub mod http_types;
 pub mod lifecycle;
 pub mod logs;
@@ -1,30 +1,128 @@
-use candid::CandidType;
+use candid::{CandidType, Principal};
 use serde::Deserialize;
 
 use crate::state::{InvalidStateError, State};
+use crate::storage::get_config;
 
-#[derive(Clone, Eq, PartialEq, Debug, CandidType, Deserialize)]
+/// The default number of retained log entries per priority.
+pub const DEFAULT_LOG_CAPACITY: u64 = 1_000;
+/// The default maximum size of the body returned by the `/logs` endpoint.
+pub const DEFAULT_MAX_LOGS_BODY_SIZE: u64 = 2_000_000;
+/// The default maximum length in bytes of a name that can be greeted.
+pub const DEFAULT_MAX_NAME_LENGTH: u64 = 1_024;
+/// The default maximum size in bytes of a log message returned by the `/logs` endpoint.
+pub const DEFAULT_MAX_LOG_MESSAGE_SIZE: u64 = 16 * 1_024;
+/// The default estimated time in seconds until the freezing threshold below
+/// which low cycles are reported.
+pub const DEFAULT_LOW_CYCLES_THRESHOLD_SECONDS: u64 = 7 * 24 * 60 * 60;
+
+#[derive(Clone, Eq, PartialEq, Debug, Default, CandidType, Deserialize)]
 pub struct InitArg {
     pub greeting: String,
+    /// Principals that are allowed to call admin endpoints in addition to
+    /// the controllers of the canister.
+    pub admins: Option<Vec<Principal>>,
+    /// The number of retained log entries per priority.
+    pub log_capacity: Option<u64>,
+    /// The maximum size of the body returned by the `/logs` endpoint.
+    pub max_logs_body_size: Option<u64>,
+    /// The maximum length in bytes of a name that can be greeted.
+    pub max_name_length: Option<u64>,
+    /// The maximum size in bytes of a log message returned by the `/logs`
+    /// endpoint, longer messages are truncated.
+    pub max_log_message_size: Option<u64>,
+    /// An error is logged and the dashboard shows a warning when the
+    /// estimated time in seconds until the freezing threshold is reached
+    /// drops below this value.
+    pub low_cycles_threshold_seconds: Option<u64>,
 }
 
-#[derive(Clone, Eq, PartialEq, Debug, CandidType, Deserialize)]
+/// Arguments for upgrading the canister. Fields that are not set keep the
+/// value of the config that was persisted by the previous installation.
+#[derive(Clone, Eq, PartialEq, Debug, Default, CandidType, Deserialize)]
 pub struct UpgradeArg {
-    pub greeting: String,
+    pub greeting: Option<String>,
+    pub admins: Option<Vec<Principal>>,
+    pub log_capacity: Option<u64>,
+    pub max_logs_body_size: Option<u64>,
+    pub max_name_length: Option<u64>,
+    pub max_log_message_size: Option<u64>,
+    pub low_cycles_threshold_seconds: Option<u64>,
 }
 
-#[derive(Clone, Debug, CandidType, Deserialize)]
+#[derive(Clone, Eq, PartialEq, Debug, CandidType, Deserialize)]
 pub enum Arg {
     InitArg(InitArg),
     UpgradeArg(UpgradeArg),
 }
 
+impl InitArg {
+    /// Returns the config resulting from applying the fields set in `upgrade_arg`.
+    pub fn upgrade(self, upgrade_arg: UpgradeArg) -> Self {
+        let UpgradeArg {
+            greeting,
+            admins,
+            log_capacity,
+            max_logs_body_size,
+            max_name_length,
+            max_log_message_size,
+            low_cycles_threshold_seconds,
+        } = upgrade_arg;
+        Self {
+            greeting: greeting.unwrap_or(self.greeting),
+            admins: admins.or(self.admins),
+            log_capacity: log_capacity.or(self.log_capacity),
+            max_logs_body_size: max_logs_body_size.or(self.max_logs_body_size),
+            max_name_length: max_name_length.or(self.max_name_length),
+            max_log_message_size: max_log_message_size.or(self.max_log_message_size),
+            low_cycles_threshold_seconds: low_cycles_threshold_seconds
+                .or(self.low_cycles_threshold_seconds),
+        }
+    }
+}
+
+impl From<&State> for InitArg {
+    fn from(state: &State) -> Self {
+        Self {
+            greeting: state.greeting.clone(),
+            admins: Some(state.admins.iter().copied().collect()),
+            log_capacity: Some(state.log_capacity),
+            max_logs_body_size: Some(state.max_logs_body_size),
+            max_name_length: Some(state.max_name_length),
+            max_log_message_size: Some(state.max_log_message_size),
+            low_cycles_threshold_seconds: Some(state.low_cycles_threshold_seconds),
+        }
+    }
+}
+
 impl TryFrom<InitArg> for State {
-    type Error = InvalidStateError;
-    fn try_from(InitArg { greeting }: InitArg) -> Result<Self, Self::Error> {
+    type Error = Vec<InvalidStateError>;
+    fn try_from(init_arg: InitArg) -> Result<Self, Self::Error> {
+        let InitArg {
+            greeting,
+            admins,
+            log_capacity,
+            max_logs_body_size,
+            max_name_length,
+            max_log_message_size,
+            low_cycles_threshold_seconds,
+        } = init_arg;
+        let max_logs_body_size = max_logs_body_size.unwrap_or(DEFAULT_MAX_LOGS_BODY_SIZE);
         let state = Self {
             greeting,
+            admins: admins.unwrap_or_default().into_iter().collect(),
+            log_capacity: log_capacity.unwrap_or(DEFAULT_LOG_CAPACITY),
+            max_logs_body_size,
+            max_name_length: max_name_length.unwrap_or(DEFAULT_MAX_NAME_LENGTH),
+            // configs persisted before this setting existed may have a smaller body size
+            max_log_message_size: max_log_message_size
+                .unwrap_or(DEFAULT_MAX_LOG_MESSAGE_SIZE.min(max_logs_body_size)),
+            low_cycles_threshold_seconds: low_cycles_threshold_seconds
+                .unwrap_or(DEFAULT_LOW_CYCLES_THRESHOLD_SECONDS),
             greeted_names_count: Default::default(),
+            total_greetings: 0,
+            last_replay_instructions: 0,
+            upgrade_history: Default::default(),
         };
         state.validate_config()?;
         Ok(state)
@@ -32,13 +130,68 @@ impl TryFrom<InitArg> for State {
 }
 
 impl TryFrom<UpgradeArg> for State {
-    type Error = InvalidStateError;
-    fn try_from(UpgradeArg { greeting }: UpgradeArg) -> Result<Self, Self::Error> {
-        let state = Self {
-            greeting,
-            greeted_names_count: Default::default(),
+    type Error = Vec<InvalidStateError>;
+    fn try_from(upgrade_arg: UpgradeArg) -> Result<Self, Self::Error> {
+        State::try_from(get_config().upgrade(upgrade_arg))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::lifecycle::{InitArg, UpgradeArg};
+    use crate::state::{InvalidStateError, State};
+
+    #[test]
+    fn should_keep_config_when_upgrade_arg_fields_are_omitted() {
+        let config = InitArg {
+            greeting: "Hello".to_string(),
+            log_capacity: Some(10),
+            ..Default::default()
         };
-        state.validate_config()?;
-        Ok(state)
+
+        assert_eq!(config.clone().upgrade(UpgradeArg::default()), config);
+        assert_eq!(
+            config.upgrade(UpgradeArg {
+                greeting: Some("Hoi".to_string()),
+                max_name_length: Some(5),
+                ..Default::default()
+            }),
+            InitArg {
+                greeting: "Hoi".to_string(),
+                log_capacity: Some(10),
+                max_name_length: Some(5),
+                ..Default::default()
+            }
+        );
+    }
+
+    #[test]
+    fn should_bound_default_max_log_message_size_by_body_size() {
+        let legacy_config = InitArg {
+            greeting: "Hello".to_string(),
+            max_logs_body_size: Some(1_000),
+            max_log_message_size: None,
+            ..Default::default()
+        };
+
+        let state = State::try_from(legacy_config).unwrap();
+
+        assert_eq!(state.max_logs_body_size, 1_000);
+        assert_eq!(state.max_log_message_size, 1_000);
+    }
+
+    #[test]
+    fn should_reject_invalid_config() {
+        assert_eq!(
+            State::try_from(InitArg {
+                greeting: " ".to_string(),
+                log_capacity: Some(0),
+                ..Default::default()
+            }),
+            Err(vec![
+                InvalidStateError::InvalidGreeting("greeting cannot be blank".to_string()),
+                InvalidStateError::InvalidLogCapacity("log capacity must be positive".to_string()),
+            ])
+        );
     }
 }
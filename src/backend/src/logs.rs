@@ -1,31 +1,264 @@
 // taken from https://github.com/dfinity/ic/blob/cfd1859fd87da4a103966d2a3f0a261bd98ad63c/rs/ethereum/cketh/minter/src/logs.rs
-use ic_canister_log::{declare_log_buffer, export as export_logs, GlobalBuffer, Sink};
+use crate::logs::rate_limit::{suppressed_log_entries, RateLimit, RATE_LIMIT_WINDOW_NANOS};
+use crate::metrics::{Labels, MetricsRegistry};
+use crate::storage::log_buffer::{
+    append_log_entry, eviction_stats, export_log_entries, export_log_entries_in, log_buffer_len,
+    log_capacity, min_log_priority,
+};
+use candid::CandidType;
+use ic_canister_log::Sink;
 use serde::Deserialize;
+use std::collections::{BTreeMap, BTreeSet};
+use std::io::Write;
+use std::ops::RangeInclusive;
 use std::str::FromStr;
+use std::time::Duration;
+
+pub mod rate_limit;
+
+// Failures that need attention.
+pub const ERROR: PrintProxySink =
+    PrintProxySink("ERROR", StableSink(Priority::Error), RateLimit::UNLIMITED);
+
+// Unexpected but recoverable situations.
+pub const WARN: PrintProxySink =
+    PrintProxySink("WARN", StableSink(Priority::Warn), RateLimit::UNLIMITED);
 
 // High-priority messages.
-declare_log_buffer!(name = INFO_BUF, capacity = 1000);
+pub const INFO: PrintProxySink = PrintProxySink(
+    "INFO",
+    StableSink(Priority::Info),
+    RateLimit::per_window(100),
+);
 
 // Low-priority info messages.
-declare_log_buffer!(name = DEBUG_BUF, capacity = 1000);
+pub const DEBUG: PrintProxySink = PrintProxySink(
+    "DEBUG",
+    StableSink(Priority::Debug),
+    RateLimit::per_window(20),
+);
+
+// Very verbose messages, e.g. for tracing the control flow.
+pub const TRACE: PrintProxySink = PrintProxySink(
+    "TRACE",
+    StableSink(Priority::Trace),
+    RateLimit::per_window(20),
+);
+
+pub use crate::storage::log_buffer::{set_log_capacity, set_min_log_priority};
 
-pub const INFO: PrintProxySink = PrintProxySink("INFO", &INFO_BUF);
-pub const DEBUG: PrintProxySink = PrintProxySink("DEBUG", &DEBUG_BUF);
+/// Reports the suppressed entries of call sites whose rate limit window ended
+/// every window, rather than only when the call site logs again.
+pub fn start_reporting_suppressed_entries() {
+    ic_cdk_timers::set_timer_interval(
+        Duration::from_nanos(RATE_LIMIT_WINDOW_NANOS),
+        report_suppressed_entries,
+    );
+}
 
+fn report_suppressed_entries() {
+    let now = ic_cdk::api::time();
+    for report in rate_limit::take_pending_reports(now) {
+        let sink = match report.priority {
+            Priority::Error => ERROR,
+            Priority::Warn => WARN,
+            Priority::Info => INFO,
+            Priority::Debug => DEBUG,
+            Priority::Trace => TRACE,
+        };
+        sink.report_suppressed(
+            ic_canister_log::LogEntry {
+                timestamp: now,
+                counter: ic_canister_log::entry_counter::increment(),
+                message: String::new(),
+                file: report.file,
+                line: report.line,
+            },
+            report.suppressed,
+        );
+    }
+}
+
+/// Adds a new entry to the log of the given sink, like [`ic_canister_log::log`].
+///
+/// Entries below the minimum log priority are dropped before the message is
+/// formatted, so they cost next to nothing.
+#[macro_export]
+macro_rules! log {
+    ($sink:expr, $($args:tt)*) => {{
+        if $sink.is_enabled() {
+            ::ic_canister_log::log!($sink, $($args)*);
+        }
+    }};
+}
+
+/// Adds a new entry with structured key-value fields to the log of the given
+/// sink. The values are recorded using their `Display` implementation.
+///
+/// ```ignore
+/// log_fields!(INFO, { caller = ic_cdk::caller(), removed = 3 }, "[compact_events]: done");
+/// ```
+#[macro_export]
+macro_rules! log_fields {
+    ($sink:expr, { $($key:ident = $value:expr),* $(,)? }, $message:expr $(,$args:expr)* $(,)?) => {{
+        if $sink.is_enabled() {
+            #[allow(unused_mut)]
+            let mut fields = $crate::logs::LogFields::new();
+            $(fields.insert(std::stringify!($key).to_string(), ($value).to_string());)*
+            $sink.append_with_fields(
+                ::ic_canister_log::LogEntry {
+                    timestamp: ::ic_canister_log::now(),
+                    message: std::format!($message $(,$args)*),
+                    file: std::file!(),
+                    line: std::line!(),
+                    counter: ::ic_canister_log::entry_counter::increment(),
+                },
+                fields,
+            );
+        }
+    }};
+}
+
+/// Structured key-value fields of a log entry.
+pub type LogFields = BTreeMap<String, String>;
+
+/// A sink that appends entries to the bounded buffer of its priority in
+/// stable memory, so that they survive canister upgrades.
 #[derive(Debug)]
-pub struct PrintProxySink(&'static str, &'static GlobalBuffer);
+pub struct StableSink(Priority);
+
+impl StableSink {
+    pub fn append_with_fields(&self, entry: ic_canister_log::LogEntry, fields: LogFields) {
+        append_log_entry(self.0, entry, fields)
+    }
+}
+
+impl Sink for StableSink {
+    fn append(&self, entry: ic_canister_log::LogEntry) {
+        self.append_with_fields(entry, LogFields::new())
+    }
+}
+
+/// A sink that prints entries to the canister log before appending them to
+/// its stable sink, keeping at most the entries allowed by its rate limit per
+/// call site.
+#[derive(Debug)]
+pub struct PrintProxySink(&'static str, StableSink, RateLimit);
+
+impl PrintProxySink {
+    /// Returns whether entries of this sink are at or above the minimum log priority.
+    pub fn is_enabled(&self) -> bool {
+        self.1 .0.is_enabled()
+    }
+
+    pub fn append_with_fields(&self, entry: ic_canister_log::LogEntry, fields: LogFields) {
+        let decision =
+            rate_limit::check(self.1 .0, self.2, entry.file, entry.line, entry.timestamp);
+        if let Some(suppressed) = decision.report_suppressed {
+            self.report_suppressed(
+                ic_canister_log::LogEntry {
+                    message: String::new(),
+                    ..entry.clone()
+                },
+                suppressed,
+            );
+        }
+        if decision.keep {
+            self.print_and_append(entry, fields);
+        }
+    }
+
+    /// Logs the number of suppressed entries of the call site of `entry`.
+    fn report_suppressed(&self, entry: ic_canister_log::LogEntry, suppressed: u64) {
+        let report = ic_canister_log::LogEntry {
+            message: format!(
+                "[log]: suppressed {} messages from {}:{}",
+                suppressed, entry.file, entry.line
+            ),
+            ..entry
+        };
+        let fields = LogFields::from([("suppressed".to_string(), suppressed.to_string())]);
+        self.print_and_append(report, fields);
+    }
+
+    fn print_and_append(&self, entry: ic_canister_log::LogEntry, fields: LogFields) {
+        ic_cdk::println!(
+            "{}",
+            format_log_line(self.0, entry.file, entry.line, &entry.message, &fields)
+        );
+        self.1.append_with_fields(entry, fields)
+    }
+}
+
+/// Formats an entry as printed to the canister log, e.g.
+/// `INFO src/main.rs:42 [compact_events]: removed 3 events removed=3`.
+fn format_log_line(
+    label: &str,
+    file: &str,
+    line: u32,
+    message: &str,
+    fields: &LogFields,
+) -> String {
+    let fields_str: String = fields
+        .iter()
+        .map(|(key, value)| format!(" {}={}", key, value))
+        .collect();
+    format!("{} {}:{} {}{}", label, file, line, message, fields_str)
+}
 
 impl Sink for PrintProxySink {
     fn append(&self, entry: ic_canister_log::LogEntry) {
-        ic_cdk::println!("{} {}:{} {}", self.0, entry.file, entry.line, entry.message);
-        self.1.append(entry)
+        self.append_with_fields(entry, LogFields::new())
     }
 }
 
-#[derive(Copy, Clone, Debug, Deserialize, serde::Serialize)]
+#[derive(
+    Copy, Clone, Eq, PartialEq, Ord, PartialOrd, Debug, CandidType, Deserialize, serde::Serialize,
+)]
 pub enum Priority {
+    Error,
+    Warn,
     Info,
     Debug,
+    Trace,
+}
+
+impl Priority {
+    /// All priorities, from the most to the least severe.
+    pub const ALL: [Priority; 5] = [
+        Priority::Error,
+        Priority::Warn,
+        Priority::Info,
+        Priority::Debug,
+        Priority::Trace,
+    ];
+
+    /// Returns how verbose the priority is, `0` being the most severe.
+    pub fn verbosity(&self) -> u8 {
+        match self {
+            Priority::Error => 0,
+            Priority::Warn => 1,
+            Priority::Info => 2,
+            Priority::Debug => 3,
+            Priority::Trace => 4,
+        }
+    }
+
+    /// Returns the lowercase name of the priority, as accepted by `FromStr`.
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Priority::Error => "error",
+            Priority::Warn => "warn",
+            Priority::Info => "info",
+            Priority::Debug => "debug",
+            Priority::Trace => "trace",
+        }
+    }
+
+    /// Returns whether entries of this priority are at or above the minimum log priority.
+    pub fn is_enabled(&self) -> bool {
+        self.verbosity() <= min_log_priority().verbosity()
+    }
 }
 
 impl FromStr for Priority {
@@ -33,14 +266,17 @@ impl FromStr for Priority {
 
     fn from_str(s: &str) -> Result<Self, Self::Err> {
         match s.to_lowercase().as_str() {
+            "error" => Ok(Priority::Error),
+            "warn" => Ok(Priority::Warn),
             "info" => Ok(Priority::Info),
             "debug" => Ok(Priority::Debug),
+            "trace" => Ok(Priority::Trace),
             _ => Err("could not recognize priority".to_string()),
         }
     }
 }
 
-#[derive(Copy, Clone, Debug, Deserialize, serde::Serialize)]
+#[derive(Copy, Clone, Eq, PartialEq, Debug, CandidType, Deserialize, serde::Serialize)]
 pub enum Sort {
     Ascending,
     Descending,
@@ -58,7 +294,306 @@ impl FromStr for Sort {
     }
 }
 
-#[derive(Clone, Debug, Deserialize, serde::Serialize)]
+/// The encoding of a `/logs` response.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum LogFormat {
+    /// A single JSON object, see [`Log`].
+    Json,
+    /// One JSON object per line and entry.
+    Ndjson,
+    /// One line per entry in the format of the canister log.
+    Text,
+    /// Comma-separated values with a header row.
+    Csv,
+}
+
+impl LogFormat {
+    pub fn content_type(&self) -> &'static str {
+        match self {
+            LogFormat::Json => "application/json; charset=utf-8",
+            LogFormat::Ndjson => "application/x-ndjson; charset=utf-8",
+            LogFormat::Text => "text/plain; charset=utf-8",
+            LogFormat::Csv => "text/csv; charset=utf-8",
+        }
+    }
+}
+
+impl FromStr for LogFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "json" => Ok(LogFormat::Json),
+            "ndjson" => Ok(LogFormat::Ndjson),
+            "text" => Ok(LogFormat::Text),
+            "csv" => Ok(LogFormat::Csv),
+            _ => Err("could not recognize format".to_string()),
+        }
+    }
+}
+
+/// Appended to messages that were truncated.
+const TRUNCATION_MARKER: &str = "…";
+
+const CSV_HEADER: &str = "timestamp,priority,file,line,counter,message,fields\n";
+
+// Writing to a `Vec<u8>` cannot fail.
+impl LogFormat {
+    fn write_header(&self, buf: &mut Vec<u8>) {
+        match self {
+            LogFormat::Json => buf.extend_from_slice(b"{\"entries\":["),
+            LogFormat::Ndjson | LogFormat::Text => {}
+            LogFormat::Csv => buf.extend_from_slice(CSV_HEADER.as_bytes()),
+        }
+    }
+
+    fn write_entry(&self, buf: &mut Vec<u8>, entry: &LogEntry, is_first: bool) {
+        match self {
+            LogFormat::Json => {
+                if !is_first {
+                    buf.push(b',');
+                }
+                serde_json::to_writer(&mut *buf, entry).unwrap();
+            }
+            LogFormat::Ndjson => {
+                serde_json::to_writer(&mut *buf, entry).unwrap();
+                buf.push(b'\n');
+            }
+            LogFormat::Text => {
+                let line = format_log_line(
+                    &entry.priority.as_str().to_uppercase(),
+                    &entry.file,
+                    entry.line,
+                    &entry.message,
+                    &entry.fields,
+                );
+                writeln!(buf, "{}", line).unwrap();
+            }
+            LogFormat::Csv => {
+                writeln!(
+                    buf,
+                    "{},{},{},{},{},{},{}",
+                    entry.timestamp,
+                    entry.priority.as_str(),
+                    escape_csv(&entry.file),
+                    entry.line,
+                    entry.counter,
+                    escape_csv(&entry.message),
+                    escape_csv(&serde_json::to_string(&entry.fields).unwrap())
+                )
+                .unwrap();
+            }
+        }
+    }
+
+    /// Writes the end of the body. Only the JSON format records whether
+    /// entries were left out or evicted, the others rely on the
+    /// `X-Next-Cursor` and `X-Entries-Evicted` headers.
+    fn write_footer(
+        &self,
+        buf: &mut Vec<u8>,
+        next_cursor: Option<LogCursor>,
+        entries_evicted: bool,
+    ) {
+        if let LogFormat::Json = self {
+            let next_cursor = next_cursor.map(|cursor| cursor.to_string());
+            write!(
+                buf,
+                "],\"truncated\":{},\"next_cursor\":{},\"entries_evicted\":{}}}",
+                next_cursor.is_some(),
+                serde_json::to_string(&next_cursor).unwrap(),
+                entries_evicted
+            )
+            .unwrap();
+        }
+    }
+}
+
+/// Quotes the value if it contains a separator, a quote or a line break.
+fn escape_csv(value: &str) -> String {
+    if value.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", value.replace('"', "\"\""))
+    } else {
+        value.to_string()
+    }
+}
+
+/// Position of an entry in the logs, used to resume a truncated `/logs` response.
+/// Formatted as `<priority>-<counter>`, e.g. `info-42`.
+#[derive(Copy, Clone, Debug, Eq, PartialEq, CandidType, Deserialize)]
+pub struct LogCursor {
+    pub priority: Priority,
+    pub counter: u64,
+}
+
+impl From<&LogEntry> for LogCursor {
+    fn from(entry: &LogEntry) -> Self {
+        Self {
+            priority: entry.priority,
+            counter: entry.counter,
+        }
+    }
+}
+
+impl std::fmt::Display for LogCursor {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}-{}", self.priority.as_str(), self.counter)
+    }
+}
+
+impl FromStr for LogCursor {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (priority, counter) = s
+            .split_once('-')
+            .ok_or_else(|| "could not recognize cursor".to_string())?;
+        Ok(Self {
+            priority: Priority::from_str(priority)?,
+            counter: u64::from_str(counter).map_err(|e| e.to_string())?,
+        })
+    }
+}
+
+/// Criteria that log entries have to match to be returned by `/logs` or
+/// `get_logs`.
+#[derive(Clone, Debug, Default, Eq, PartialEq, CandidType, Deserialize)]
+pub struct LogFilter {
+    /// Only entries of this priority.
+    pub priority: Option<Priority>,
+    /// Only entries logged in this file.
+    pub file: Option<String>,
+    /// Only entries logged on this line.
+    pub line: Option<u32>,
+    /// Only entries whose message contains this substring.
+    pub contains: Option<String>,
+    /// Only entries with a timestamp at or after this one, in nanoseconds.
+    pub since: Option<u64>,
+    /// Only entries with a timestamp at or before this one, in nanoseconds.
+    pub until: Option<u64>,
+    /// Only entries with a counter at or above this one.
+    pub min_counter: Option<u64>,
+    /// Only entries with a counter at or below this one.
+    pub max_counter: Option<u64>,
+    /// Only entries with all of these fields set to the given values.
+    pub fields: Vec<(String, String)>,
+    /// Skips the entries preceding this one in the requested order.
+    pub cursor: Option<LogCursor>,
+    /// The order of the entries, see [`LogFilter::sort_order`].
+    pub sort: Option<Sort>,
+}
+
+impl LogFilter {
+    /// Parses the filter from the query parameters of a `/logs` request.
+    /// Unknown parameters, unrecognized priorities and sort orders are ignored.
+    /// Like [`crate::http_types::HttpRequest::raw_query_param`], only the
+    /// first occurrence of a repeated parameter is used, except for `field.*`.
+    pub fn from_query_params(
+        params: impl Iterator<Item = (impl AsRef<str>, impl AsRef<str>)>,
+    ) -> Result<Self, String> {
+        fn parse<T: FromStr>(param: &str, value: &str) -> Result<Option<T>, String> {
+            T::from_str(value)
+                .map(Some)
+                .map_err(|_| format!("failed to parse the '{}' parameter", param))
+        }
+
+        let mut filter = Self::default();
+        let mut seen = BTreeSet::new();
+        for (param, value) in params {
+            let (param, value) = (param.as_ref(), value.as_ref());
+            if !param.starts_with("field.") && !seen.insert(param.to_string()) {
+                continue;
+            }
+            match param {
+                "priority" => filter.priority = Priority::from_str(value).ok(),
+                "file" => filter.file = Some(value.to_string()),
+                "line" => filter.line = parse(param, value)?,
+                "contains" => filter.contains = Some(value.to_string()),
+                "time" => filter.since = parse(param, value)?,
+                "until" => filter.until = parse(param, value)?,
+                "min_counter" => filter.min_counter = parse(param, value)?,
+                "max_counter" => filter.max_counter = parse(param, value)?,
+                "cursor" => filter.cursor = parse(param, value)?,
+                "sort" => filter.sort = Sort::from_str(value).ok(),
+                _ => {
+                    if let Some(key) = param.strip_prefix("field.") {
+                        filter.fields.push((key.to_string(), value.to_string()));
+                    }
+                }
+            }
+        }
+        Ok(filter)
+    }
+
+    /// Returns whether entries of the requested priorities and time range were
+    /// evicted from the log buffers.
+    pub fn includes_evicted_entries(&self) -> bool {
+        Priority::ALL
+            .into_iter()
+            .filter(|priority| self.priority.is_none_or(|p| p == *priority))
+            .map(eviction_stats)
+            .any(|stats| {
+                stats.evicted > 0
+                    && self
+                        .since
+                        .is_none_or(|since| since <= stats.last_evicted_timestamp)
+            })
+    }
+
+    /// Returns the requested order, which defaults to ascending unless a
+    /// start time is given.
+    pub fn sort_order(&self) -> Sort {
+        self.sort.unwrap_or(match self.since {
+            None | Some(0) => Sort::Ascending,
+            Some(_) => Sort::Descending,
+        })
+    }
+
+    /// Returns the range of counters of the entries matching the filter,
+    /// starting at the cursor in the requested order. As counters are unique
+    /// across priorities, the range also resumes after an evicted cursor.
+    pub fn counter_range(&self) -> RangeInclusive<u64> {
+        let sort_order = self.sort_order();
+        let cursor = |order: Sort| {
+            self.cursor
+                .filter(|_| sort_order == order)
+                .map(|cursor| cursor.counter)
+        };
+        let min = self
+            .min_counter
+            .into_iter()
+            .chain(cursor(Sort::Ascending))
+            .max();
+        let max = self
+            .max_counter
+            .into_iter()
+            .chain(cursor(Sort::Descending))
+            .min();
+        min.unwrap_or(u64::MIN)..=max.unwrap_or(u64::MAX)
+    }
+
+    /// Returns whether the entry matches all criteria of the filter.
+    pub fn matches(&self, entry: &LogEntry) -> bool {
+        self.priority
+            .is_none_or(|priority| entry.priority == priority)
+            && self.file.as_ref().is_none_or(|file| &entry.file == file)
+            && self.line.is_none_or(|line| entry.line == line)
+            && self
+                .contains
+                .as_ref()
+                .is_none_or(|substring| entry.message.contains(substring.as_str()))
+            && self.since.is_none_or(|since| entry.timestamp >= since)
+            && self.until.is_none_or(|until| entry.timestamp <= until)
+            && self.min_counter.is_none_or(|min| entry.counter >= min)
+            && self.max_counter.is_none_or(|max| entry.counter <= max)
+            && self
+                .fields
+                .iter()
+                .all(|(key, value)| entry.fields.get(key) == Some(value))
+    }
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize, serde::Serialize)]
 pub struct LogEntry {
     pub timestamp: u64,
     pub priority: Priority,
@@ -66,58 +601,182 @@ pub struct LogEntry {
     pub line: u32,
     pub message: String,
     pub counter: u64,
+    #[serde(default)]
+    pub fields: LogFields,
+    /// The number of bytes that were cut from the end of the message.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub truncated_bytes: Option<u64>,
 }
 
 #[derive(Clone, Debug, Default, Deserialize, serde::Serialize)]
 pub struct Log {
     pub entries: Vec<LogEntry>,
+    /// Whether entries were left out to bound the size of the response.
+    #[serde(default)]
+    pub truncated: bool,
+    /// The cursor of the first entry that was left out, if any.
+    #[serde(default)]
+    pub next_cursor: Option<String>,
+    /// Whether the requested time range starts before the oldest retained
+    /// entry, i.e. whether matching entries may have been evicted.
+    #[serde(default)]
+    pub entries_evicted: bool,
+}
+
+/// The entries returned by the `get_logs` query, bounded like a `/logs`
+/// response.
+#[derive(Clone, Debug, Default, CandidType, Deserialize)]
+pub struct LogPage {
+    pub entries: Vec<LogEntry>,
+    /// Whether entries were left out to bound the size of the response.
+    pub truncated: bool,
+    /// The cursor of the first entry that was left out, if any. Set it as the
+    /// `cursor` of the same filter to get the next page.
+    pub next_cursor: Option<LogCursor>,
+    /// Whether the requested time range starts before the oldest retained
+    /// entry, i.e. whether matching entries may have been evicted.
+    pub entries_evicted: bool,
 }
 
 impl Log {
     pub fn push_logs(&mut self, priority: Priority) {
-        let logs = match priority {
-            Priority::Info => export_logs(&INFO_BUF),
-            Priority::Debug => export_logs(&DEBUG_BUF),
-        };
-        for entry in logs {
-            self.entries.push(LogEntry {
-                timestamp: entry.timestamp,
-                counter: entry.counter,
-                priority,
-                file: entry.file.to_string(),
-                line: entry.line,
-                message: entry.message,
-            });
-        }
+        self.entries.extend(export_log_entries(priority));
     }
 
     pub fn push_all(&mut self) {
-        self.push_logs(Priority::Info);
-        self.push_logs(Priority::Debug);
+        for priority in Priority::ALL {
+            self.push_logs(priority);
+        }
+    }
+
+    /// Adds the entries matching the filter. Only the entries within
+    /// [`LogFilter::counter_range`] are read from stable memory.
+    pub fn push_filtered(&mut self, filter: &LogFilter) {
+        let counters = filter.counter_range();
+        if counters.is_empty() {
+            return;
+        }
+        for priority in Priority::ALL {
+            if filter.priority.is_none_or(|p| p == priority) {
+                self.entries
+                    .extend(export_log_entries_in(priority, counters.clone()));
+            }
+        }
+        self.entries.retain(|entry| filter.matches(entry));
+    }
+
+    /// Returns the entries matching the filter in the requested order,
+    /// starting at the cursor of the filter.
+    pub fn query(filter: &LogFilter) -> Self {
+        let mut log = Self::default();
+        log.push_filtered(filter);
+        log.sort_logs(filter.sort_order());
+        log.entries_evicted = filter.includes_evicted_entries();
+        log
+    }
+
+    /// Leaves out the trailing entries that exceed `max_body_size` when
+    /// serialized as JSON, marking the log as truncated. Returns the cursor of
+    /// the first entry that was left out, if any.
+    pub fn limit_size(&mut self, max_body_size: usize) -> Option<LogCursor> {
+        let (_, next_cursor) = self.serialize_logs_as(LogFormat::Json, max_body_size);
+        if let Some(cursor) = next_cursor {
+            let len = self
+                .entries
+                .iter()
+                .position(|entry| LogCursor::from(entry) == cursor)
+                .unwrap_or(self.entries.len());
+            self.entries.truncate(len);
+            self.truncated = true;
+            self.next_cursor = Some(cursor.to_string());
+        }
+        next_cursor
+    }
+
+    /// Drops the entries preceding the cursor. If the entry of the cursor was
+    /// evicted in the meantime, resumes at the next entry in the sort order.
+    pub fn skip_to_cursor(&mut self, cursor: LogCursor, sort_order: Sort) {
+        let start = self
+            .entries
+            .iter()
+            .position(|entry| LogCursor::from(entry) == cursor)
+            .or_else(|| {
+                self.entries.iter().position(|entry| match sort_order {
+                    Sort::Ascending => entry.counter > cursor.counter,
+                    Sort::Descending => entry.counter < cursor.counter,
+                })
+            })
+            .unwrap_or(self.entries.len());
+        self.entries.drain(..start);
+    }
+
+    /// Truncates the messages that are longer than `max_message_size` bytes,
+    /// so that a single huge entry does not crowd out all others.
+    pub fn truncate_messages(&mut self, max_message_size: usize) {
+        for entry in &mut self.entries {
+            if entry.message.len() <= max_message_size {
+                continue;
+            }
+            // the marker is left out if it does not fit
+            let marker = if max_message_size >= TRUNCATION_MARKER.len() {
+                TRUNCATION_MARKER
+            } else {
+                ""
+            };
+            let mut len = max_message_size - marker.len();
+            while !entry.message.is_char_boundary(len) {
+                len -= 1;
+            }
+            let truncated_bytes = entry.message.len() - len;
+            entry.message.truncate(len);
+            entry.message.push_str(marker);
+            entry.truncated_bytes = Some(truncated_bytes as u64);
+        }
     }
 
+    /// Serializes the logs as JSON, leaving out the trailing entries that
+    /// exceed `max_body_size` and marking the result as truncated.
     pub fn serialize_logs(&self, max_body_size: usize) -> String {
-        let mut entries_json: String = serde_json::to_string(&self).unwrap_or_default();
-
-        if entries_json.len() > max_body_size {
-            let mut left = 0;
-            let mut right = self.entries.len();
-
-            while left < right {
-                let mid = left + (right - left) / 2;
-                let mut temp_log = self.clone();
-                temp_log.entries.truncate(mid);
-                let temp_entries_json = serde_json::to_string(&temp_log).unwrap_or_default();
-
-                if temp_entries_json.len() <= max_body_size {
-                    entries_json = temp_entries_json;
-                    left = mid + 1;
-                } else {
-                    right = mid;
-                }
+        self.serialize_logs_as(LogFormat::Json, max_body_size).0
+    }
+
+    /// Serializes the logs in the given format, leaving out the trailing
+    /// entries that exceed `max_body_size`. Returns the cursor of the first
+    /// entry that was left out, if any.
+    ///
+    /// The entries are serialized one after the other while keeping track of
+    /// the size of the body, so that the cost is linear in the size of the
+    /// returned body.
+    pub fn serialize_logs_as(
+        &self,
+        format: LogFormat,
+        max_body_size: usize,
+    ) -> (String, Option<LogCursor>) {
+        let mut body = Vec::new();
+        let mut footer = Vec::new();
+        let mut next_cursor = None;
+
+        format.write_header(&mut body);
+        for (index, entry) in self.entries.iter().enumerate() {
+            let body_len = body.len();
+            format.write_entry(&mut body, entry, index == 0);
+
+            footer.clear();
+            format.write_footer(
+                &mut footer,
+                self.entries.get(index + 1).map(LogCursor::from),
+                self.entries_evicted,
+            );
+            if body.len() + footer.len() > max_body_size {
+                body.truncate(body_len);
+                next_cursor = Some(LogCursor::from(entry));
+                break;
             }
         }
-        entries_json
+        format.write_footer(&mut body, next_cursor, self.entries_evicted);
+
+        let body = String::from_utf8(body).expect("serialized logs should be valid UTF-8");
+        (body, next_cursor)
     }
 
     pub fn sort_logs(&mut self, sort_order: Sort) {
@@ -127,19 +786,68 @@ impl Log {
         }
     }
 
+    /// Sorts by timestamp, breaking ties by the counter of the entries so that
+    /// the order is stable across paginated requests.
     pub fn sort_asc(&mut self) {
-        self.entries.sort_by(|a, b| a.timestamp.cmp(&b.timestamp));
+        self.entries
+            .sort_by_key(|entry| (entry.timestamp, entry.counter));
     }
 
     pub fn sort_desc(&mut self) {
-        self.entries.sort_by(|a, b| b.timestamp.cmp(&a.timestamp));
+        self.entries
+            .sort_by_key(|entry| std::cmp::Reverse((entry.timestamp, entry.counter)));
     }
 }
 
+/// Registers the metrics of the log buffers.
+pub fn register_metrics(registry: &mut MetricsRegistry) {
+    fn per_priority<V>(value: impl Fn(Priority) -> V) -> Vec<(Labels, V)> {
+        Priority::ALL
+            .into_iter()
+            .map(|priority| {
+                (
+                    vec![("priority", priority.as_str().to_string())],
+                    value(priority),
+                )
+            })
+            .collect()
+    }
+
+    registry.gauge_vec(
+        "log_buffer_capacity",
+        "Number of retained log entries per priority.",
+        || {
+            log_capacity()
+                .map(|capacity| (vec![], capacity))
+                .into_iter()
+                .collect()
+        },
+    );
+    registry.gauge_vec(
+        "log_buffer_entries",
+        "Number of log entries in the buffer of a priority.",
+        || per_priority(log_buffer_len),
+    );
+    registry.counter_vec(
+        "log_evicted_entries_total",
+        "Number of log entries evicted from the buffer of a priority.",
+        || per_priority(|priority| eviction_stats(priority).evicted),
+    );
+    registry.counter_vec(
+        "log_suppressed_entries_total",
+        "Number of log entries of a priority suppressed by rate limiting or sampling since the last upgrade.",
+        || per_priority(suppressed_log_entries),
+    );
+}
+
 #[cfg(test)]
 mod tests {
-    use crate::logs::{Log, LogEntry, Priority, Sort};
-    use proptest::{prop_assert, proptest};
+    use crate::logs::{
+        set_min_log_priority, Log, LogCursor, LogEntry, LogFilter, LogFormat, Priority, Sort,
+    };
+    use crate::storage::log_buffer::{append_log_entry, set_log_capacity};
+    use proptest::{prop_assert, prop_assert_eq, proptest};
+    use std::str::FromStr;
 
     fn info_log_entry_with_timestamp(timestamp: u64) -> LogEntry {
         LogEntry {
@@ -149,6 +857,8 @@ mod tests {
             line: 0,
             message: String::default(),
             counter: 0,
+            fields: Default::default(),
+            truncated_bytes: None,
         }
     }
 
@@ -186,17 +896,339 @@ mod tests {
                     line: 0,
                     message: "1".repeat(entry_size),
                     counter: 0,
+                    fields: Default::default(),
+                    truncated_bytes: None,
                 });
             }
-            let log = Log { entries };
+            let log = Log {
+                entries,
+                ..Default::default()
+            };
             let truncated_logs_json_len = log.serialize_logs(max_body_size).len();
             prop_assert!(truncated_logs_json_len <= max_body_size);
         }
+
+        #[test]
+        fn logs_are_truncated_after_the_last_fitting_entry(
+            entry_sizes in proptest::collection::vec(0..1000_usize, 1..50),
+            max_body_size in (100..20000_usize)
+        ) {
+            let entries: Vec<LogEntry> = entry_sizes
+                .iter()
+                .enumerate()
+                .map(|(counter, entry_size)| LogEntry {
+                    counter: counter as u64,
+                    message: "1".repeat(*entry_size),
+                    ..info_log_entry_with_timestamp(0)
+                })
+                .collect();
+            let log = Log {
+                entries: entries.clone(),
+                ..Default::default()
+            };
+            // serializes the first `len` entries the way the derived implementation does
+            let reference = |len: usize| {
+                serde_json::to_string(&Log {
+                    entries: entries[..len].to_vec(),
+                    truncated: len < entries.len(),
+                    next_cursor: entries.get(len).map(|entry| LogCursor::from(entry).to_string()),
+                    entries_evicted: false,
+                })
+                .unwrap()
+            };
+
+            let serialized = log.serialize_logs(max_body_size);
+            let len = serde_json::from_str::<Log>(&serialized).unwrap().entries.len();
+            prop_assert_eq!(&serialized, &reference(len));
+            if len < entries.len() {
+                prop_assert!(reference(len + 1).len() > max_body_size);
+            }
+        }
+    }
+
+    #[test]
+    fn should_filter_by_min_log_priority() {
+        assert_eq!(Priority::from_str("WARN"), Ok(Priority::Warn));
+        assert!(Priority::Debug.is_enabled());
+        assert!(!Priority::Trace.is_enabled());
+
+        set_min_log_priority(Priority::Warn);
+        assert!(Priority::Error.is_enabled());
+        assert!(Priority::Warn.is_enabled());
+        assert!(!Priority::Info.is_enabled());
+        assert!(!crate::logs::INFO.is_enabled());
+
+        set_min_log_priority(Priority::Trace);
+        assert!(Priority::ALL.iter().all(Priority::is_enabled));
+    }
+
+    fn filter(query: &str) -> LogFilter {
+        LogFilter::from_query_params(
+            query
+                .split('&')
+                .map(|param| param.split_once('=').unwrap_or((param, ""))),
+        )
+        .unwrap()
+    }
+
+    fn matching_counters(filter: &LogFilter, entries: &[LogEntry]) -> Vec<u64> {
+        entries
+            .iter()
+            .filter(|entry| filter.matches(entry))
+            .map(|entry| entry.counter)
+            .collect()
+    }
+
+    fn entries_with<F: Fn(&mut LogEntry, u64)>(count: u64, f: F) -> Vec<LogEntry> {
+        (0..count)
+            .map(|counter| {
+                let mut entry = LogEntry {
+                    counter,
+                    ..info_log_entry_with_timestamp(counter * 10)
+                };
+                f(&mut entry, counter);
+                entry
+            })
+            .collect()
+    }
+
+    #[test]
+    fn should_parse_log_filter_from_query_params() {
+        assert_eq!(filter("unknown=1&priority=loud"), LogFilter::default());
+        assert_eq!(
+            filter("priority=warn&file=src/main.rs&line=7&contains=abc&time=1&until=2&min_counter=3&max_counter=4&field.caller=alice&cursor=warn-5"),
+            LogFilter {
+                priority: Some(Priority::Warn),
+                file: Some("src/main.rs".to_string()),
+                line: Some(7),
+                contains: Some("abc".to_string()),
+                since: Some(1),
+                until: Some(2),
+                min_counter: Some(3),
+                max_counter: Some(4),
+                fields: vec![("caller".to_string(), "alice".to_string())],
+                cursor: Some(LogCursor {
+                    priority: Priority::Warn,
+                    counter: 5
+                }),
+                sort: None,
+            }
+        );
+        assert_eq!(
+            LogFilter::from_query_params([("until", "yesterday")].into_iter()),
+            Err("failed to parse the 'until' parameter".to_string())
+        );
+        assert_eq!(
+            filter("priority=warn&priority=error&line=1&line=x&field.a=1&field.a=2"),
+            LogFilter {
+                priority: Some(Priority::Warn),
+                line: Some(1),
+                fields: vec![
+                    ("a".to_string(), "1".to_string()),
+                    ("a".to_string(), "2".to_string())
+                ],
+                ..Default::default()
+            }
+        );
+    }
+
+    #[test]
+    fn should_report_evicted_entries_in_requested_range() {
+        for timestamp in 0..5 {
+            append_log_entry(
+                Priority::Debug,
+                ic_canister_log::LogEntry {
+                    timestamp,
+                    counter: 0,
+                    message: String::default(),
+                    file: file!(),
+                    line: line!(),
+                },
+                Default::default(),
+            );
+        }
+        assert!(!filter("").includes_evicted_entries());
+
+        set_log_capacity(3);
+        assert!(filter("").includes_evicted_entries());
+        assert!(filter("priority=debug&time=1").includes_evicted_entries());
+        assert!(!filter("priority=debug&time=2").includes_evicted_entries());
+        assert!(!filter("priority=info").includes_evicted_entries());
+
+        let log = Log::query(&filter("priority=debug"));
+        assert_eq!(log.entries.len(), 3);
+        assert!(log
+            .serialize_logs(usize::MAX)
+            .ends_with("\"entries_evicted\":true}"));
+    }
+
+    #[test]
+    fn should_query_entries_in_counter_range() {
+        assert_eq!(filter("").counter_range(), 0..=u64::MAX);
+        assert_eq!(filter("min_counter=3&max_counter=5").counter_range(), 3..=5);
+        assert_eq!(
+            filter("min_counter=3&cursor=info-4").counter_range(),
+            4..=u64::MAX
+        );
+        assert_eq!(
+            filter("max_counter=5&cursor=info-4&sort=desc").counter_range(),
+            0..=4
+        );
+        assert!(filter("max_counter=3&cursor=info-4")
+            .counter_range()
+            .is_empty());
+
+        for (index, priority) in [Priority::Info, Priority::Warn, Priority::Info]
+            .into_iter()
+            .cycle()
+            .take(6)
+            .enumerate()
+        {
+            append_log_entry(
+                priority,
+                ic_canister_log::LogEntry {
+                    timestamp: index as u64,
+                    counter: 0,
+                    message: String::default(),
+                    file: file!(),
+                    line: line!(),
+                },
+                Default::default(),
+            );
+        }
+        let counters = |query: &str| -> Vec<u64> {
+            Log::query(&filter(query))
+                .entries
+                .into_iter()
+                .map(|entry| entry.counter)
+                .collect()
+        };
+        assert_eq!(counters("cursor=warn-4"), vec![4, 5]);
+        assert_eq!(counters("cursor=warn-4&sort=desc"), vec![4, 3, 2, 1, 0]);
+        assert_eq!(
+            counters("priority=info&cursor=warn-4&sort=desc"),
+            vec![3, 2, 0]
+        );
+        assert!(counters("max_counter=3&cursor=info-4").is_empty());
+    }
+
+    #[test]
+    fn should_default_sort_order_to_time_filter() {
+        assert_eq!(filter("").sort_order(), Sort::Ascending);
+        assert_eq!(filter("time=0").sort_order(), Sort::Ascending);
+        assert_eq!(filter("time=1").sort_order(), Sort::Descending);
+        assert_eq!(filter("time=1&sort=unknown").sort_order(), Sort::Descending);
+        assert_eq!(filter("time=1&sort=asc").sort_order(), Sort::Ascending);
+    }
+
+    #[test]
+    fn should_filter_by_priority() {
+        let entries = entries_with(3, |entry, counter| {
+            entry.priority = Priority::ALL[counter as usize]
+        });
+        assert_eq!(
+            matching_counters(&filter("priority=info"), &entries),
+            vec![2]
+        );
+        assert_eq!(matching_counters(&filter(""), &entries), vec![0, 1, 2]);
+    }
+
+    #[test]
+    fn should_filter_by_file_and_line() {
+        let entries = entries_with(4, |entry, counter| {
+            entry.file = format!("src/{}.rs", counter % 2);
+            entry.line = counter as u32;
+        });
+        assert_eq!(
+            matching_counters(&filter("file=src/1.rs"), &entries),
+            vec![1, 3]
+        );
+        assert_eq!(
+            matching_counters(&filter("file=src/1.rs&line=3"), &entries),
+            vec![3]
+        );
+        assert!(matching_counters(&filter("file=1.rs"), &entries).is_empty());
+    }
+
+    #[test]
+    fn should_filter_by_message_substring() {
+        let entries = entries_with(3, |entry, counter| {
+            entry.message = format!("[greet]: message {}", counter)
+        });
+        assert_eq!(
+            matching_counters(&filter("contains=message"), &entries),
+            vec![0, 1, 2]
+        );
+        assert_eq!(
+            matching_counters(&filter("contains=e 2"), &entries),
+            vec![2]
+        );
+        assert!(matching_counters(&filter("contains=Message"), &entries).is_empty());
+    }
+
+    #[test]
+    fn should_filter_by_time_range() {
+        let entries = entries_with(5, |_, _| {});
+        assert_eq!(
+            matching_counters(&filter("time=10"), &entries),
+            vec![1, 2, 3, 4]
+        );
+        assert_eq!(
+            matching_counters(&filter("until=25"), &entries),
+            vec![0, 1, 2]
+        );
+        assert_eq!(
+            matching_counters(&filter("time=10&until=30"), &entries),
+            vec![1, 2, 3]
+        );
+    }
+
+    #[test]
+    fn should_filter_by_counter_range() {
+        let entries = entries_with(5, |_, _| {});
+        assert_eq!(
+            matching_counters(&filter("min_counter=3"), &entries),
+            vec![3, 4]
+        );
+        assert_eq!(
+            matching_counters(&filter("max_counter=1"), &entries),
+            vec![0, 1]
+        );
+        assert_eq!(
+            matching_counters(&filter("min_counter=1&max_counter=2"), &entries),
+            vec![1, 2]
+        );
+    }
+
+    #[test]
+    fn should_filter_by_fields() {
+        let entries = entries_with(3, |entry, counter| {
+            entry.fields = match counter {
+                0 => [("caller", "alice"), ("removed", "3")].as_slice(),
+                1 => [("caller", "bob")].as_slice(),
+                _ => [].as_slice(),
+            }
+            .iter()
+            .map(|(key, value)| (key.to_string(), value.to_string()))
+            .collect()
+        });
+        assert_eq!(
+            serde_json::to_value(&entries[0]).unwrap()["fields"],
+            serde_json::json!({"caller": "alice", "removed": "3"})
+        );
+
+        assert_eq!(
+            matching_counters(&filter("field.caller=alice"), &entries),
+            vec![0]
+        );
+        assert!(
+            matching_counters(&filter("field.caller=alice&field.removed=4"), &entries).is_empty()
+        );
     }
 
     #[test]
     fn sorting_order() {
-        let mut log = Log { entries: vec![] };
+        let mut log = Log::default();
         log.entries.push(info_log_entry_with_timestamp(2));
         log.entries.push(info_log_entry_with_timestamp(0));
         log.entries.push(info_log_entry_with_timestamp(1));
@@ -213,6 +1245,190 @@ mod tests {
         assert!(is_descending(&log));
     }
 
+    fn paginate(entries: &[LogEntry], sort_order: Sort, max_body_size: usize) -> Vec<LogEntry> {
+        let mut pages: Vec<LogEntry> = vec![];
+        let mut cursor = None;
+        loop {
+            let mut log = Log {
+                entries: entries.to_vec(),
+                ..Default::default()
+            };
+            log.sort_logs(sort_order);
+            if let Some(cursor) = cursor {
+                log.skip_to_cursor(cursor, sort_order);
+            }
+            let page: Log = serde_json::from_str(&log.serialize_logs(max_body_size)).unwrap();
+            assert!(!page.entries.is_empty());
+            pages.extend(page.entries);
+            match page.next_cursor {
+                Some(next_cursor) => cursor = Some(LogCursor::from_str(&next_cursor).unwrap()),
+                None => {
+                    assert!(!page.truncated);
+                    return pages;
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn should_resume_truncated_logs_at_cursor() {
+        // entries logged in the same message share their timestamp
+        let entries = entries_with(20, |entry, counter| {
+            entry.timestamp = counter / 4;
+            entry.priority = Priority::ALL[(counter % 5) as usize];
+        });
+        let counters = |entries: Vec<LogEntry>| -> Vec<u64> {
+            entries.into_iter().map(|entry| entry.counter).collect()
+        };
+        let single_page_size = serde_json::to_string(&Log {
+            entries: entries[..3].to_vec(),
+            ..Default::default()
+        })
+        .unwrap()
+        .len()
+            + 50;
+
+        assert_eq!(
+            counters(paginate(&entries, Sort::Ascending, single_page_size)),
+            (0..20).collect::<Vec<_>>()
+        );
+        assert_eq!(
+            counters(paginate(&entries, Sort::Descending, single_page_size)),
+            (0..20).rev().collect::<Vec<_>>()
+        );
+    }
+
+    #[test]
+    fn should_resume_after_evicted_cursor() {
+        let cursor = LogCursor::from_str("debug-7").unwrap();
+        assert_eq!(cursor.to_string(), "debug-7");
+        assert!(LogCursor::from_str("debug").is_err());
+
+        let entries: Vec<LogEntry> = entries_with(10, |_, _| {})
+            .into_iter()
+            .filter(|entry| entry.counter != 7)
+            .collect();
+        let mut log = Log {
+            entries: entries.clone(),
+            ..Default::default()
+        };
+        log.skip_to_cursor(cursor, Sort::Ascending);
+        assert_eq!(log.entries[0].counter, 8);
+
+        let mut log = Log {
+            entries,
+            ..Default::default()
+        };
+        log.sort_desc();
+        log.skip_to_cursor(cursor, Sort::Descending);
+        assert_eq!(log.entries[0].counter, 6);
+    }
+
+    fn entries_for_formats() -> Vec<LogEntry> {
+        entries_with(2, |entry, counter| {
+            entry.file = "src/main.rs".to_string();
+            entry.line = 7;
+            entry.message = format!("hello, \"world\" {}", counter);
+            if counter == 1 {
+                entry
+                    .fields
+                    .insert("caller".to_string(), "alice".to_string());
+            }
+        })
+    }
+
+    #[test]
+    fn should_parse_log_format() {
+        assert_eq!(LogFormat::from_str("NDJSON"), Ok(LogFormat::Ndjson));
+        assert!(LogFormat::from_str("xml").is_err());
+        assert_eq!(LogFormat::Csv.content_type(), "text/csv; charset=utf-8");
+    }
+
+    #[test]
+    fn should_serialize_logs_as_ndjson() {
+        let log = Log {
+            entries: entries_for_formats(),
+            ..Default::default()
+        };
+        let (body, next_cursor) = log.serialize_logs_as(LogFormat::Ndjson, usize::MAX);
+        assert_eq!(next_cursor, None);
+        let lines: Vec<&str> = body.lines().collect();
+        assert_eq!(lines.len(), 2);
+        let entry: LogEntry = serde_json::from_str(lines[1]).unwrap();
+        assert_eq!(entry.message, "hello, \"world\" 1");
+        assert_eq!(entry.fields.get("caller").unwrap(), "alice");
+    }
+
+    #[test]
+    fn should_serialize_logs_as_text() {
+        let log = Log {
+            entries: entries_for_formats(),
+            ..Default::default()
+        };
+        let (body, _) = log.serialize_logs_as(LogFormat::Text, usize::MAX);
+        assert_eq!(
+            body,
+            "INFO src/main.rs:7 hello, \"world\" 0\n\
+             INFO src/main.rs:7 hello, \"world\" 1 caller=alice\n"
+        );
+    }
+
+    #[test]
+    fn should_serialize_logs_as_csv() {
+        let log = Log {
+            entries: entries_for_formats(),
+            ..Default::default()
+        };
+        let (body, _) = log.serialize_logs_as(LogFormat::Csv, usize::MAX);
+        assert_eq!(
+            body,
+            "timestamp,priority,file,line,counter,message,fields\n\
+             0,info,src/main.rs,7,0,\"hello, \"\"world\"\" 0\",{}\n\
+             10,info,src/main.rs,7,1,\"hello, \"\"world\"\" 1\",\"{\"\"caller\"\":\"\"alice\"\"}\"\n"
+        );
+    }
+
+    #[test]
+    fn should_bound_size_of_every_format() {
+        let log = Log {
+            entries: entries_for_formats(),
+            ..Default::default()
+        };
+        for format in [
+            LogFormat::Json,
+            LogFormat::Ndjson,
+            LogFormat::Text,
+            LogFormat::Csv,
+        ] {
+            let (full_body, _) = log.serialize_logs_as(format, usize::MAX);
+            let (body, next_cursor) = log.serialize_logs_as(format, full_body.len() - 1);
+            assert!(body.len() < full_body.len());
+            assert_eq!(next_cursor, Some(LogCursor::from(&log.entries[1])));
+        }
+    }
+
+    #[test]
+    fn should_limit_size_like_serialization() {
+        let log = Log {
+            entries: entries_with(10, |_, _| {}),
+            ..Default::default()
+        };
+        let max_body_size = log.serialize_logs(usize::MAX).len() / 2;
+
+        let mut limited_log = log.clone();
+        limited_log.limit_size(max_body_size);
+        assert!(limited_log.truncated);
+        assert_eq!(
+            serde_json::to_string(&limited_log).unwrap(),
+            log.serialize_logs(max_body_size)
+        );
+
+        let mut unlimited_log = log.clone();
+        unlimited_log.limit_size(usize::MAX);
+        assert_eq!(unlimited_log.entries.len(), 10);
+        assert!(!unlimited_log.truncated);
+    }
+
     #[test]
     fn simple_logs_truncation() {
         let mut entries: Vec<LogEntry> = vec![];
@@ -226,10 +1442,15 @@ mod tests {
                 line: 0,
                 message: String::default(),
                 counter: 0,
+                fields: Default::default(),
+                truncated_bytes: None,
             });
         }
         let log = Log {
             entries: entries.clone(),
+            truncated: true,
+            next_cursor: Some("info-10".to_string()),
+            entries_evicted: false,
         };
         let small_len = serde_json::to_string(&log).unwrap_or_default().len();
 
@@ -239,9 +1460,14 @@ mod tests {
             file: String::default(),
             line: 0,
             message: "1".repeat(MAX_BODY_SIZE),
-            counter: 0,
+            counter: 10,
+            fields: Default::default(),
+            truncated_bytes: None,
         });
-        let log = Log { entries };
+        let log = Log {
+            entries,
+            ..Default::default()
+        };
         let entries_json = serde_json::to_string(&log).unwrap_or_default();
         assert!(entries_json.len() > MAX_BODY_SIZE);
 
@@ -254,6 +1480,7 @@ mod tests {
     fn one_entry_too_big() {
         let mut entries: Vec<LogEntry> = vec![];
         const MAX_BODY_SIZE: usize = 3_000_000;
+        const MAX_MESSAGE_SIZE: usize = 1_000;
 
         entries.push(LogEntry {
             timestamp: 0,
@@ -262,11 +1489,63 @@ mod tests {
             line: 0,
             message: "1".repeat(MAX_BODY_SIZE),
             counter: 0,
+            fields: Default::default(),
+            truncated_bytes: None,
         });
-        let log = Log { entries };
-        let truncated_logs_json_len = log.serialize_logs(MAX_BODY_SIZE).len();
-        assert!(truncated_logs_json_len < MAX_BODY_SIZE);
-        assert_eq!("{\"entries\":[]}", log.serialize_logs(MAX_BODY_SIZE));
+        entries.push(info_log_entry_with_timestamp(1));
+        let mut log = Log {
+            entries,
+            ..Default::default()
+        };
+        log.truncate_messages(MAX_MESSAGE_SIZE);
+        let truncated_logs_json = log.serialize_logs(MAX_BODY_SIZE);
+        assert!(truncated_logs_json.len() < MAX_BODY_SIZE);
+
+        let truncated_log: Log = serde_json::from_str(&truncated_logs_json).unwrap();
+        assert!(!truncated_log.truncated);
+        assert_eq!(truncated_log.entries.len(), 2);
+        let entry = &truncated_log.entries[0];
+        assert_eq!(entry.message.len(), MAX_MESSAGE_SIZE);
+        assert!(entry.message.ends_with("1…"));
+        assert_eq!(
+            entry.truncated_bytes,
+            Some((MAX_BODY_SIZE - MAX_MESSAGE_SIZE + "…".len()) as u64)
+        );
+        assert_eq!(truncated_log.entries[1].truncated_bytes, None);
+        assert!(!truncated_logs_json.contains("\"truncated_bytes\":null"));
+    }
+
+    #[test]
+    fn should_truncate_messages_at_char_boundary() {
+        let mut log = Log {
+            entries: vec![LogEntry {
+                message: "ééé".to_string(),
+                ..info_log_entry_with_timestamp(0)
+            }],
+            ..Default::default()
+        };
+        log.truncate_messages(5);
+        assert_eq!(log.entries[0].message, "é…");
+        assert_eq!(log.entries[0].truncated_bytes, Some(4));
+    }
+
+    #[test]
+    fn should_truncate_messages_without_marker_below_marker_size() {
+        for (max_message_size, expected) in [(2, "é"), (1, "")] {
+            let mut log = Log {
+                entries: vec![LogEntry {
+                    message: "ééé".to_string(),
+                    ..info_log_entry_with_timestamp(0)
+                }],
+                ..Default::default()
+            };
+            log.truncate_messages(max_message_size);
+            assert_eq!(log.entries[0].message, expected);
+            assert_eq!(
+                log.entries[0].truncated_bytes,
+                Some(6 - expected.len() as u64)
+            );
+        }
     }
 
     #[test]
@@ -282,12 +1561,16 @@ mod tests {
                 entries.pop();
                 entries
             },
+            truncated: true,
+            next_cursor: Some(LogCursor::from(&log_entries[2]).to_string()),
+            entries_evicted: false,
         };
         let log_with_3_entries = Log {
             entries: log_entries,
+            ..Default::default()
         };
 
-        let serialized_log_with_2_entries = log_with_2_entries.serialize_logs(usize::MAX);
+        let serialized_log_with_2_entries = serde_json::to_string(&log_with_2_entries).unwrap();
         let serialized_log_with_3_entries =
             log_with_3_entries.serialize_logs(serialized_log_with_2_entries.len());
 
@@ -0,0 +1,240 @@
+//! Rate limiting and sampling of log entries per call site, so that a hot
+//! call site cannot evict the history of all others from the log buffers.
+use crate::logs::Priority;
+use std::cell::RefCell;
+use std::collections::BTreeMap;
+
+/// The length of the window in which the entries of a call site are counted.
+pub const RATE_LIMIT_WINDOW_NANOS: u64 = 60 * 1_000_000_000;
+
+/// Limits the entries that a sink keeps per call site.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct RateLimit {
+    /// The maximum number of entries per call site and window.
+    pub max_entries_per_window: Option<u64>,
+    /// Only every `sample_one_in`-th entry of a call site is kept.
+    pub sample_one_in: u64,
+}
+
+impl RateLimit {
+    pub const UNLIMITED: RateLimit = RateLimit {
+        max_entries_per_window: None,
+        sample_one_in: 1,
+    };
+
+    pub const fn per_window(max_entries_per_window: u64) -> Self {
+        Self {
+            max_entries_per_window: Some(max_entries_per_window),
+            sample_one_in: 1,
+        }
+    }
+
+    pub const fn sampled(self, sample_one_in: u64) -> Self {
+        Self {
+            sample_one_in,
+            ..self
+        }
+    }
+}
+
+/// Whether an entry is kept, and the number of entries of its call site that
+/// were suppressed in the previous windows and have not been reported yet.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct Decision {
+    pub keep: bool,
+    pub report_suppressed: Option<u64>,
+}
+
+/// The entries of a call site that were suppressed in its previous windows
+/// and have not been reported yet.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct PendingReport {
+    pub priority: Priority,
+    pub file: &'static str,
+    pub line: u32,
+    pub suppressed: u64,
+}
+
+struct CallSite {
+    priority: Priority,
+    window_start: u64,
+    kept_in_window: u64,
+    seen: u64,
+    suppressed: u64,
+}
+
+thread_local! {
+    static CALL_SITES: RefCell<BTreeMap<(&'static str, u32), CallSite>> = RefCell::default();
+    static SUPPRESSED: RefCell<BTreeMap<Priority, u64>> = RefCell::default();
+}
+
+/// Decides whether to keep an entry logged at `file:line` at time `now`.
+pub fn check(
+    priority: Priority,
+    limit: RateLimit,
+    file: &'static str,
+    line: u32,
+    now: u64,
+) -> Decision {
+    if limit == RateLimit::UNLIMITED {
+        return Decision {
+            keep: true,
+            report_suppressed: None,
+        };
+    }
+    CALL_SITES.with_borrow_mut(|call_sites| {
+        let call_site = call_sites.entry((file, line)).or_insert_with(|| CallSite {
+            priority,
+            window_start: now,
+            kept_in_window: 0,
+            seen: 0,
+            suppressed: 0,
+        });
+        let mut report_suppressed = None;
+        if now.saturating_sub(call_site.window_start) >= RATE_LIMIT_WINDOW_NANOS {
+            call_site.window_start = now;
+            call_site.kept_in_window = 0;
+            if call_site.suppressed > 0 {
+                report_suppressed = Some(std::mem::take(&mut call_site.suppressed));
+            }
+        }
+
+        call_site.seen += 1;
+        let sampled_out = (call_site.seen - 1) % limit.sample_one_in.max(1) != 0;
+        let limited = limit
+            .max_entries_per_window
+            .is_some_and(|max| call_site.kept_in_window >= max);
+        let keep = !sampled_out && !limited;
+        if keep {
+            call_site.kept_in_window += 1;
+        } else {
+            call_site.suppressed += 1;
+            SUPPRESSED.with_borrow_mut(|suppressed| *suppressed.entry(priority).or_default() += 1);
+        }
+        Decision {
+            keep,
+            report_suppressed,
+        }
+    })
+}
+
+/// Takes the suppressed entries of the call sites whose window ended at `now`
+/// and starts their next window, so that the entries are reported even if the
+/// call site does not log again.
+pub fn take_pending_reports(now: u64) -> Vec<PendingReport> {
+    CALL_SITES.with_borrow_mut(|call_sites| {
+        call_sites
+            .iter_mut()
+            .filter(|(_, call_site)| {
+                call_site.suppressed > 0
+                    && now.saturating_sub(call_site.window_start) >= RATE_LIMIT_WINDOW_NANOS
+            })
+            .map(|((file, line), call_site)| {
+                call_site.window_start = now;
+                call_site.kept_in_window = 0;
+                PendingReport {
+                    priority: call_site.priority,
+                    file,
+                    line: *line,
+                    suppressed: std::mem::take(&mut call_site.suppressed),
+                }
+            })
+            .collect()
+    })
+}
+
+/// Returns the number of entries of the given priority that were suppressed
+/// since the canister was installed or upgraded.
+pub fn suppressed_log_entries(priority: Priority) -> u64 {
+    SUPPRESSED.with_borrow(|suppressed| suppressed.get(&priority).copied().unwrap_or_default())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::logs::rate_limit::{
+        check, suppressed_log_entries, take_pending_reports, Decision, PendingReport, RateLimit,
+        RATE_LIMIT_WINDOW_NANOS,
+    };
+    use crate::logs::Priority;
+
+    fn kept(limit: RateLimit, line: u32, now: u64) -> bool {
+        let decision = check(Priority::Debug, limit, "src/main.rs", line, now);
+        decision.keep
+    }
+
+    #[test]
+    fn should_limit_entries_per_call_site_and_window() {
+        let limit = RateLimit::per_window(2);
+        assert!(kept(limit, 1, 0));
+        assert!(kept(limit, 1, 1));
+        assert!(!kept(limit, 1, 2));
+        assert!(!kept(limit, 1, 3));
+        // other call sites are not affected
+        assert!(kept(limit, 2, 3));
+        assert_eq!(suppressed_log_entries(Priority::Debug), 2);
+
+        assert_eq!(
+            check(
+                Priority::Debug,
+                limit,
+                "src/main.rs",
+                1,
+                RATE_LIMIT_WINDOW_NANOS
+            ),
+            Decision {
+                keep: true,
+                report_suppressed: Some(2),
+            }
+        );
+        assert!(kept(limit, 1, RATE_LIMIT_WINDOW_NANOS + 1));
+        assert!(!kept(limit, 1, RATE_LIMIT_WINDOW_NANOS + 2));
+    }
+
+    #[test]
+    fn should_take_pending_reports_of_ended_windows() {
+        let limit = RateLimit::per_window(1);
+        assert!(kept(limit, 1, 0));
+        assert!(!kept(limit, 1, 1));
+        assert!(kept(limit, 2, 10));
+        assert!(!kept(limit, 2, 11));
+
+        assert_eq!(take_pending_reports(RATE_LIMIT_WINDOW_NANOS - 1), vec![]);
+        assert_eq!(
+            take_pending_reports(RATE_LIMIT_WINDOW_NANOS),
+            vec![PendingReport {
+                priority: Priority::Debug,
+                file: "src/main.rs",
+                line: 1,
+                suppressed: 1,
+            }]
+        );
+        // the report starts the next window of the call site
+        assert!(kept(limit, 1, RATE_LIMIT_WINDOW_NANOS + 1));
+        assert_eq!(
+            check(
+                Priority::Debug,
+                limit,
+                "src/main.rs",
+                2,
+                RATE_LIMIT_WINDOW_NANOS + 10
+            ),
+            Decision {
+                keep: true,
+                report_suppressed: Some(1),
+            }
+        );
+        assert_eq!(
+            take_pending_reports(2 * RATE_LIMIT_WINDOW_NANOS + 10),
+            vec![]
+        );
+    }
+
+    #[test]
+    fn should_sample_entries_per_call_site() {
+        let limit = RateLimit::UNLIMITED.sampled(3);
+        let kept: Vec<bool> = (0..7).map(|now| kept(limit, 1, now)).collect();
+        assert_eq!(kept, vec![true, false, false, true, false, false, true]);
+        assert_eq!(suppressed_log_entries(Priority::Debug), 4);
+        assert_eq!(suppressed_log_entries(Priority::Info), 0);
+    }
+}
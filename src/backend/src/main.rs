@@ -1,43 +1,149 @@
 use backend::dashboard::DashboardTemplate;
-use backend::lifecycle::Arg;
-use backend::logs::INFO;
-use backend::state::audit::replay_events;
-use backend::state::{mutate_state, State};
-use backend::storage::record_event;
+use backend::guard::{
+    caller_is_admin, caller_is_controller, not_in_replicated_execution, state_is_initialized,
+};
+use backend::lifecycle::{Arg, InitArg};
+use backend::logs::{
+    set_log_capacity, set_min_log_priority, start_reporting_suppressed_entries, Log, LogFilter,
+    LogPage, Priority, INFO,
+};
+use backend::metrics::endpoints::{observe_call, record_guard_rejection};
+use backend::state::audit::{self, process_event, replay_events, CompactionStatus};
+use backend::state::event::Event;
+use backend::state::{mutate_state, State, UpgradeRecord};
+use backend::storage::schema::{self, set_schema_version, MigrationStatus, SCHEMA_VERSION};
+use backend::storage::{is_unused_event_log_zeroed, record_event, set_config};
+use backend::{log, log_fields};
 use backend::{
-    metrics::encode_metrics,
-    state::{self, initialize_state, read_state},
+    metrics::{collect_metrics, history::start_sampling, MetricFamily},
+    state::{initialize_state, read_state},
 };
-use ic_canister_log::log;
+use std::str::FromStr;
+use std::time::Duration;
+
+/// Rejects names that are too long in the guard rather than trapping in
+/// `greet`, so that the rejection is counted.
+fn greet_guard() -> Result<(), String> {
+    record_guard_rejection(
+        "greet",
+        state_is_initialized().and_then(|()| {
+            let (name,): (String,) = ic_cdk::api::call::arg_data(Default::default());
+            if name.len() as u64 > read_state(|s| s.max_name_length) {
+                return Err("name is too long".to_string());
+            }
+            Ok(())
+        }),
+    )
+}
 
-#[ic_cdk::update]
+#[ic_cdk::update(guard = "greet_guard")]
 fn greet(name: String) -> String {
-    record_event(name.clone());
+    let _call = observe_call("greet");
     // insert the name into the greeted_names_count map
-    mutate_state(|s| {
-        s.greeted_names_count
-            .entry(name.clone())
-            .and_modify(|count| *count += 1)
-            .or_insert(1);
-    });
+    mutate_state(|s| process_event(s, Event::Greeted(name.clone())));
     format!("{}, {}!", read_state(|s| s.greeting.clone()), name)
 }
 
-#[ic_cdk::query]
-fn total_greeted_names_count() -> u64 {
-    // this helps avoding expensive query calls to be executed in replicated mode
-    if ic_cdk::api::in_replicated_execution() {
-        ic_cdk::trap("update call rejected");
+fn forget_name_guard() -> Result<(), String> {
+    record_guard_rejection("forget_name", caller_is_admin())
+}
+
+#[ic_cdk::update(guard = "forget_name_guard")]
+fn forget_name(name: String) {
+    let _call = observe_call("forget_name");
+    mutate_state(|s| process_event(s, Event::Forgotten(name)));
+    log_fields!(
+        INFO,
+        { caller = ic_cdk::caller() },
+        "[forget_name]: forgot a name"
+    );
+}
+
+fn compact_events_guard() -> Result<(), String> {
+    record_guard_rejection("compact_events", caller_is_admin())
+}
+
+/// Starts compacting the event log. The compaction is resumed in timers if it
+/// does not fit into this message, a compaction in progress is not restarted.
+#[ic_cdk::update(guard = "compact_events_guard")]
+fn compact_events() -> CompactionStatus {
+    let _call = observe_call("compact_events");
+    if audit::is_compacting() {
+        return CompactionStatus::Pending;
+    }
+    log_fields!(
+        INFO,
+        { caller = ic_cdk::caller() },
+        "[compact_events]: started compacting the event log"
+    );
+    resume_compaction()
+}
+
+/// Resumes zeroing the memory of an event log that was replaced before the
+/// last upgrade.
+fn resume_zeroing_previous_event_log() {
+    if !audit::zero_previous_event_log() {
+        ic_cdk_timers::set_timer(Duration::ZERO, resume_zeroing_previous_event_log);
+    }
+}
+
+fn resume_compaction() -> CompactionStatus {
+    let status = audit::compact_events();
+    match status {
+        CompactionStatus::Completed { removed_events } => log_fields!(
+            INFO,
+            { removed = removed_events },
+            "[compact_events]: removed {} events",
+            removed_events
+        ),
+        CompactionStatus::Pending => {
+            ic_cdk_timers::set_timer(Duration::ZERO, || {
+                resume_compaction();
+            });
+        }
     }
+    status
+}
+
+fn set_min_log_level_guard() -> Result<(), String> {
+    record_guard_rejection("set_min_log_level", caller_is_controller())
+}
+
+/// Sets the minimum priority of log entries, entries below it are dropped.
+#[ic_cdk::update(guard = "set_min_log_level_guard")]
+fn set_min_log_level(priority: Priority) {
+    let _call = observe_call("set_min_log_level");
+    set_min_log_priority(priority);
+    log!(
+        INFO,
+        "[set_min_log_level]: set minimum log level to {:?}",
+        priority
+    );
+}
+
+fn total_greeted_names_count_guard() -> Result<(), String> {
+    record_guard_rejection(
+        "total_greeted_names_count",
+        not_in_replicated_execution().and_then(|()| state_is_initialized()),
+    )
+}
+
+#[ic_cdk::query(guard = "total_greeted_names_count_guard")]
+fn total_greeted_names_count() -> u64 {
+    let _call = observe_call("total_greeted_names_count");
     read_state(|s| s.greeted_names_count.len() as u64)
 }
 
-#[ic_cdk::query]
+fn greeted_name_count_guard() -> Result<(), String> {
+    record_guard_rejection(
+        "greeted_name_count",
+        not_in_replicated_execution().and_then(|()| state_is_initialized()),
+    )
+}
+
+#[ic_cdk::query(guard = "greeted_name_count_guard")]
 fn greeted_name_count(name: String) -> u64 {
-    // this helps avoding expensive query calls to be executed in replicated mode
-    if ic_cdk::api::in_replicated_execution() {
-        ic_cdk::trap("update call rejected");
-    }
+    let _call = observe_call("greeted_name_count");
     read_state(|s| {
         s.greeted_names_count
             .get(&name)
@@ -46,15 +152,68 @@ fn greeted_name_count(name: String) -> u64 {
     })
 }
 
+fn validate_upgrade_arg_guard() -> Result<(), String> {
+    record_guard_rejection("validate_upgrade_arg", caller_is_controller())
+}
+
+/// Dry-runs an upgrade with the given argument against the persisted config.
+/// Returns the resulting config, or all reasons why the upgrade would fail.
+#[ic_cdk::query(guard = "validate_upgrade_arg_guard")]
+fn validate_upgrade_arg(arg: Arg) -> Result<InitArg, Vec<String>> {
+    let _call = observe_call("validate_upgrade_arg");
+    let upgrade_arg = match arg {
+        Arg::InitArg(_) => {
+            return Err(vec![
+                "cannot upgrade canister state with init args".to_string()
+            ]);
+        }
+        Arg::UpgradeArg(upgrade_arg) => upgrade_arg,
+    };
+    let mut errors = vec![];
+    if let Err(err) = schema::check_schema_version() {
+        errors.push(format!("cannot upgrade stable memory: {:?}", err));
+    }
+    match State::try_from(upgrade_arg) {
+        Ok(state) if errors.is_empty() => Ok(InitArg::from(&state)),
+        Ok(_) => Err(errors),
+        Err(config_errors) => {
+            errors.extend(config_errors.iter().map(ToString::to_string));
+            Err(errors)
+        }
+    }
+}
+
+fn get_upgrade_history_guard() -> Result<(), String> {
+    record_guard_rejection("get_upgrade_history", state_is_initialized())
+}
+
+#[ic_cdk::query(guard = "get_upgrade_history_guard")]
+fn get_upgrade_history() -> Vec<UpgradeRecord> {
+    let _call = observe_call("get_upgrade_history");
+    read_state(|s| s.upgrade_history.clone())
+}
+
+fn upgraded_event(arg: Arg) -> Event {
+    Event::Upgraded {
+        canister_version: ic_cdk::api::canister_version(),
+        timestamp: ic_cdk::api::time(),
+        arg,
+    }
+}
+
 #[ic_cdk::init]
 fn init(arg: Arg) {
-    match arg {
+    match arg.clone() {
         Arg::InitArg(init_arg) => {
             log!(INFO, "[init]: initialized minter with arg: {:?}", init_arg);
-            initialize_state(
-                state::State::try_from(init_arg.clone())
-                    .expect("BUG: failed to initialize canister"),
-            );
+            let state = State::try_from(init_arg).expect("BUG: failed to initialize canister");
+            set_config(InitArg::from(&state));
+            initialize_state(state);
+            set_schema_version(SCHEMA_VERSION);
+            read_state(|s| set_log_capacity(s.log_capacity));
+            mutate_state(|s| process_event(s, upgraded_event(arg)));
+            start_sampling();
+            start_reporting_suppressed_entries();
         }
         Arg::UpgradeArg(_) => {
             ic_cdk::trap("cannot init canister state with upgrade args");
@@ -64,123 +223,229 @@ fn init(arg: Arg) {
 
 #[ic_cdk::post_upgrade]
 fn post_upgrade(arg: Arg) {
-    match arg {
+    match arg.clone() {
         Arg::InitArg(_) => {
             ic_cdk::trap("cannot upgrade canister state with init args");
         }
         Arg::UpgradeArg(upgrade_arg) => {
-            initialize_state(State {
-                greeted_names_count: {
-                    #[cfg(feature = "canbench-rs")]
-                    let _p = canbench_rs::bench_scope("replay_events");
-                    replay_events()
-                },
-                ..State::try_from(upgrade_arg.clone()).expect("BUG: failed to initialize canister")
-            });
+            // this has to happen before touching any other stable structure
+            if let Err(err) = schema::check_schema_version() {
+                ic_cdk::trap(&format!("cannot upgrade stable memory: {:?}", err));
+            }
+            let state =
+                State::try_from(upgrade_arg.clone()).expect("BUG: failed to initialize canister");
+            set_config(InitArg::from(&state));
+            set_log_capacity(state.log_capacity);
             log!(
                 INFO,
                 "[upgrade]: upgraded canister with arg: {:?}",
                 upgrade_arg
             );
+            migrate_and_initialize_state(state, upgraded_event(arg));
+            if !is_unused_event_log_zeroed() {
+                ic_cdk_timers::set_timer(Duration::ZERO, resume_zeroing_previous_event_log);
+            }
+            start_sampling();
+            start_reporting_suppressed_entries();
         }
     }
 }
 
-#[ic_cdk::query(hidden = true)]
+/// Runs the pending stable memory migrations, then records the `upgraded`
+/// event and initializes the state from the event log. If the migrations do
+/// not fit into the current message, they are resumed in a timer, and the
+/// state stays uninitialized until then.
+fn migrate_and_initialize_state(mut state: State, upgraded: Event) {
+    match schema::migrate() {
+        Ok(MigrationStatus::Completed) => {
+            record_event(&upgraded);
+            {
+                #[cfg(feature = "canbench-rs")]
+                let _p = canbench_rs::bench_scope("replay_events");
+                let start = ic_cdk::api::instruction_counter();
+                replay_events(&mut state);
+                state.last_replay_instructions = ic_cdk::api::instruction_counter() - start;
+            }
+            initialize_state(state);
+        }
+        Ok(MigrationStatus::Pending) => {
+            log!(
+                INFO,
+                "[upgrade]: stable memory migration to version {} is pending",
+                SCHEMA_VERSION
+            );
+            ic_cdk_timers::set_timer(Duration::ZERO, move || {
+                migrate_and_initialize_state(state, upgraded)
+            });
+        }
+        Err(err) => ic_cdk::trap(&format!("cannot upgrade stable memory: {:?}", err)),
+    }
+}
+
+fn get_metrics_guard() -> Result<(), String> {
+    record_guard_rejection("get_metrics", state_is_initialized())
+}
+
+/// Returns the same metrics as the `/metrics` endpoint.
+#[ic_cdk::query(guard = "get_metrics_guard")]
+fn get_metrics() -> Vec<MetricFamily> {
+    let _call = observe_call("get_metrics");
+    collect_metrics()
+}
+
+fn get_logs_guard() -> Result<(), String> {
+    record_guard_rejection(
+        "get_logs",
+        not_in_replicated_execution().and_then(|()| state_is_initialized()),
+    )
+}
+
+/// Returns the log entries matching the filter, bounded like the `/logs` endpoint.
+#[ic_cdk::query(guard = "get_logs_guard")]
+fn get_logs(filter: LogFilter) -> LogPage {
+    let _call = observe_call("get_logs");
+    let mut log = read_logs(&filter);
+    let next_cursor = log.limit_size(read_state(|s| s.max_logs_body_size) as usize);
+    LogPage {
+        entries: log.entries,
+        truncated: log.truncated,
+        next_cursor,
+        entries_evicted: log.entries_evicted,
+    }
+}
+
+/// Returns the log entries matching the filter with oversized messages truncated.
+fn read_logs(filter: &LogFilter) -> Log {
+    let mut log = Log::query(filter);
+    log.truncate_messages(read_state(|s| s.max_log_message_size) as usize);
+    log
+}
+
+fn http_request_guard() -> Result<(), String> {
+    record_guard_rejection("http_request", not_in_replicated_execution())
+}
+
+#[ic_cdk::query(hidden = true, guard = "http_request_guard")]
 fn http_request(req: backend::http_types::HttpRequest) -> backend::http_types::HttpResponse {
     use backend::http_types::HttpResponseBuilder;
+    let _call = observe_call("http_request");
 
-    // this helps avoding expensive query calls to be executed in replicated mode
-    if ic_cdk::api::in_replicated_execution() {
-        ic_cdk::trap("update call rejected");
+    if let Err(reason) = state_is_initialized() {
+        return HttpResponseBuilder::service_unavailable(reason).build();
     }
 
     if req.path() == "/metrics" {
-        let mut writer =
-            ic_metrics_encoder::MetricsEncoder::new(vec![], ic_cdk::api::time() as i64 / 1_000_000);
+        use backend::metrics::format::MetricsFormat;
+
+        let accept = req
+            .headers
+            .iter()
+            .find(|(name, _)| name.eq_ignore_ascii_case("accept"))
+            .map(|(_, value)| value.as_str());
+        let format =
+            match MetricsFormat::negotiate(req.raw_query_param("format").as_deref(), accept) {
+                Ok(format) => format,
+                Err(_) => {
+                    return HttpResponseBuilder::bad_request()
+                        .with_body_and_content_length("failed to parse the 'format' parameter")
+                        .build();
+                }
+            };
 
-        match encode_metrics(&mut writer) {
-            Ok(()) => HttpResponseBuilder::ok()
-                .header("Content-Type", "text/plain; version=0.0.4")
-                .with_body_and_content_length(writer.into_inner())
+        match format.encode(&collect_metrics(), ic_cdk::api::time() as i64 / 1_000_000) {
+            Ok(body) => HttpResponseBuilder::ok()
+                .header("Content-Type", format.content_type())
+                .with_body_and_content_length(body)
                 .build(),
             Err(err) => {
                 HttpResponseBuilder::server_error(format!("Failed to encode metrics: {}", err))
                     .build()
             }
         }
+    } else if req.path() == "/metrics/history" {
+        use backend::storage::metrics_history::{metrics_history_since, MAX_METRICS_HISTORY_LIMIT};
+
+        let since = match req
+            .raw_query_param("since")
+            .map(|since| u64::from_str(&since))
+        {
+            None => 0,
+            Some(Ok(since)) => since,
+            Some(Err(_)) => {
+                return HttpResponseBuilder::bad_request()
+                    .with_body_and_content_length("failed to parse the 'since' parameter")
+                    .build();
+            }
+        };
+        let limit = match req
+            .raw_query_param("limit")
+            .map(|limit| usize::from_str(&limit))
+        {
+            None => MAX_METRICS_HISTORY_LIMIT,
+            Some(Ok(limit)) => limit.min(MAX_METRICS_HISTORY_LIMIT),
+            Some(Err(_)) => {
+                return HttpResponseBuilder::bad_request()
+                    .with_body_and_content_length("failed to parse the 'limit' parameter")
+                    .build();
+            }
+        };
+        match serde_json::to_string(&metrics_history_since(since, limit)) {
+            Ok(body) => HttpResponseBuilder::ok()
+                .header("Content-Type", "application/json")
+                .with_body_and_content_length(body)
+                .build(),
+            Err(err) => HttpResponseBuilder::server_error(format!(
+                "Failed to encode metrics history: {}",
+                err
+            ))
+            .build(),
+        }
     } else if req.path() == "/dashboard" {
         use askama::Template;
 
-        let dashboard = read_state(DashboardTemplate::from_state);
+        use backend::metrics::history::cycles_runway;
+
+        let cycles_runway = cycles_runway();
+        let dashboard = read_state(|s| DashboardTemplate::from_state(s, cycles_runway));
         HttpResponseBuilder::ok()
             .header("Content-Type", "text/html; charset=utf-8")
             .with_body_and_content_length(dashboard.render().unwrap())
             .build()
     } else if req.path() == "/logs" {
-        use backend::logs::{Log, Priority, Sort};
-        use std::str::FromStr;
+        use backend::logs::LogFormat;
 
-        let max_skip_timestamp = match req.raw_query_param("time") {
-            Some(arg) => match u64::from_str(arg) {
-                Ok(value) => value,
-                Err(_) => {
-                    return HttpResponseBuilder::bad_request()
-                        .with_body_and_content_length("failed to parse the 'time' parameter")
-                        .build();
-                }
-            },
-            None => 0,
+        let filter = match LogFilter::from_query_params(req.raw_query_params()) {
+            Ok(filter) => filter,
+            Err(reason) => {
+                return HttpResponseBuilder::bad_request()
+                    .with_body_and_content_length(reason)
+                    .build();
+            }
+        };
+        let format = match req
+            .raw_query_param("format")
+            .map(|format| LogFormat::from_str(&format))
+        {
+            None => LogFormat::Json,
+            Some(Ok(format)) => format,
+            Some(Err(_)) => {
+                return HttpResponseBuilder::bad_request()
+                    .with_body_and_content_length("failed to parse the 'format' parameter")
+                    .build();
+            }
         };
 
-        let mut log: Log = Default::default();
-
-        match req.raw_query_param("priority") {
-            Some(priority_str) => match Priority::from_str(priority_str) {
-                Ok(priority) => match priority {
-                    Priority::Info => log.push_logs(Priority::Info),
-                    Priority::Debug => log.push_logs(Priority::Debug),
-                },
-                Err(_) => log.push_all(),
-            },
-            None => log.push_all(),
+        let log = read_logs(&filter);
+        let max_body_size = read_state(|s| s.max_logs_body_size) as usize;
+        let (body, next_cursor) = log.serialize_logs_as(format, max_body_size);
+        let mut response = HttpResponseBuilder::ok().header("Content-Type", format.content_type());
+        if let Some(next_cursor) = next_cursor {
+            response = response.header("X-Next-Cursor", next_cursor);
         }
-
-        log.entries
-            .retain(|entry| entry.timestamp >= max_skip_timestamp);
-
-        fn ordering_from_query_params(sort: Option<&str>, max_skip_timestamp: u64) -> Sort {
-            match sort {
-                Some(ord_str) => match Sort::from_str(ord_str) {
-                    Ok(order) => order,
-                    Err(_) => {
-                        if max_skip_timestamp == 0 {
-                            Sort::Ascending
-                        } else {
-                            Sort::Descending
-                        }
-                    }
-                },
-                None => {
-                    if max_skip_timestamp == 0 {
-                        Sort::Ascending
-                    } else {
-                        Sort::Descending
-                    }
-                }
-            }
+        if log.entries_evicted {
+            response = response.header("X-Entries-Evicted", "true");
         }
-
-        log.sort_logs(ordering_from_query_params(
-            req.raw_query_param("sort"),
-            max_skip_timestamp,
-        ));
-
-        const MAX_BODY_SIZE: usize = 2_000_000;
-        HttpResponseBuilder::ok()
-            .header("Content-Type", "application/json; charset=utf-8")
-            .with_body_and_content_length(log.serialize_logs(MAX_BODY_SIZE))
-            .build()
+        response.with_body_and_content_length(body).build()
     } else {
         HttpResponseBuilder::not_found().build()
     }
@@ -210,7 +475,7 @@ mod benches {
     #[bench]
     fn insert_events() {
         for i in 0..1_000_000 {
-            record_event(i.to_string());
+            record_event(&Event::Greeted(i.to_string()));
         }
     }
 
@@ -230,6 +495,44 @@ mod benches {
         })
     }
 
+    fn log_with_entries(count: u64) -> backend::logs::Log {
+        use backend::logs::{Log, LogEntry};
+
+        Log {
+            entries: (0..count)
+                .map(|counter| LogEntry {
+                    timestamp: counter,
+                    priority: Priority::Info,
+                    file: "src/backend/src/main.rs".to_string(),
+                    line: 42,
+                    message: format!("[greet]: greeted name {}", counter),
+                    counter,
+                    fields: Default::default(),
+                    truncated_bytes: None,
+                })
+                .collect(),
+            ..Default::default()
+        }
+    }
+
+    // Benchmarks serializing 1k log entries into a `/logs` response.
+    #[bench(raw)]
+    fn serialize_1k_log_entries() -> canbench_rs::BenchResult {
+        let log = log_with_entries(1_000);
+        canbench_rs::bench_fn(|| {
+            log.serialize_logs(backend::lifecycle::DEFAULT_MAX_LOGS_BODY_SIZE as usize)
+        })
+    }
+
+    // Benchmarks serializing 10k log entries into a `/logs` response.
+    #[bench(raw)]
+    fn serialize_10k_log_entries() -> canbench_rs::BenchResult {
+        let log = log_with_entries(10_000);
+        canbench_rs::bench_fn(|| {
+            log.serialize_logs(backend::lifecycle::DEFAULT_MAX_LOGS_BODY_SIZE as usize)
+        })
+    }
+
     #[bench(raw)]
     fn post_upgrade_bench() -> canbench_rs::BenchResult {
         // this shouldnt affect the benchmark results as
@@ -240,7 +543,8 @@ mod benches {
         // included in the results of our benchmark.
         canbench_rs::bench_fn(|| {
             post_upgrade(Arg::UpgradeArg(UpgradeArg {
-                greeting: "hoi".to_string(),
+                greeting: Some("hoi".to_string()),
+                ..Default::default()
             }))
         })
     }
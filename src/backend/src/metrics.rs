@@ -1,33 +1,325 @@
 // taken from https://github.com/dfinity/evm-rpc-canister/blob/b3ebd0900ed59cdffc7e79644954734617d3a1e9/src/metrics.rs
+use crate::state::read_state;
+use crate::storage::total_event_count;
 use crate::types::MetricValue;
+use candid::CandidType;
 use ic_metrics_encoder::MetricsEncoder;
+use serde::Deserialize;
+
+pub mod endpoints;
+pub mod format;
+pub mod history;
+
+/// The labels of a sample, e.g. `[("priority", "info".to_string())]`.
+pub type Labels = Vec<(&'static str, String)>;
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq, CandidType, Deserialize)]
+pub enum MetricKind {
+    Counter,
+    Gauge,
+    Histogram,
+}
+
+/// The observations of a histogram.
+#[derive(Clone, Debug, Default, PartialEq, CandidType, Deserialize)]
+pub struct Histogram {
+    /// The upper bound of each bucket and the number of observations *in*
+    /// the bucket, i.e. not cumulative. The last bound should be `+Inf`.
+    pub buckets: Vec<(f64, f64)>,
+    /// The sum of all observed values.
+    pub sum: f64,
+}
+
+#[derive(Clone, Debug, PartialEq, CandidType, Deserialize)]
+pub enum SampleValue {
+    Value(f64),
+    Histogram(Histogram),
+}
+
+#[derive(Clone, Debug, PartialEq, CandidType, Deserialize)]
+pub struct Sample {
+    pub labels: Vec<(String, String)>,
+    pub value: SampleValue,
+}
+
+/// A metric with all its samples at the time it was collected. This is the
+/// single source of the `/metrics` endpoint and the `get_metrics` query.
+#[derive(Clone, Debug, PartialEq, CandidType, Deserialize)]
+pub struct MetricFamily {
+    pub name: String,
+    pub help: String,
+    pub kind: MetricKind,
+    pub samples: Vec<Sample>,
+}
+
+struct RegisteredMetric {
+    name: &'static str,
+    help: &'static str,
+    kind: MetricKind,
+    collect: Box<dyn Fn() -> Vec<Sample>>,
+}
+
+/// The metrics exposed by the canister. Each metric is registered once with
+/// a function that reads its current value, and is collected on every scrape
+/// in the order in which it was registered.
+#[derive(Default)]
+pub struct MetricsRegistry {
+    metrics: Vec<RegisteredMetric>,
+}
+
+impl MetricsRegistry {
+    pub fn counter<V: MetricValue>(
+        &mut self,
+        name: &'static str,
+        help: &'static str,
+        value: impl Fn() -> V + 'static,
+    ) {
+        self.counter_vec(name, help, move || vec![(vec![], value())]);
+    }
+
+    pub fn counter_vec<V: MetricValue>(
+        &mut self,
+        name: &'static str,
+        help: &'static str,
+        values: impl Fn() -> Vec<(Labels, V)> + 'static,
+    ) {
+        self.register(name, help, MetricKind::Counter, move || {
+            scalar_samples(values())
+        });
+    }
+
+    pub fn gauge<V: MetricValue>(
+        &mut self,
+        name: &'static str,
+        help: &'static str,
+        value: impl Fn() -> V + 'static,
+    ) {
+        self.gauge_vec(name, help, move || vec![(vec![], value())]);
+    }
+
+    pub fn gauge_vec<V: MetricValue>(
+        &mut self,
+        name: &'static str,
+        help: &'static str,
+        values: impl Fn() -> Vec<(Labels, V)> + 'static,
+    ) {
+        self.register(name, help, MetricKind::Gauge, move || {
+            scalar_samples(values())
+        });
+    }
+
+    pub fn histogram_vec(
+        &mut self,
+        name: &'static str,
+        help: &'static str,
+        values: impl Fn() -> Vec<(Labels, Histogram)> + 'static,
+    ) {
+        self.register(name, help, MetricKind::Histogram, move || {
+            values()
+                .into_iter()
+                .map(|(labels, histogram)| Sample {
+                    labels: owned_labels(labels),
+                    value: SampleValue::Histogram(histogram),
+                })
+                .collect()
+        });
+    }
+
+    /// Registers a metric.
+    ///
+    /// # Panics
+    ///
+    /// Panics if a metric with the same name was already registered.
+    fn register(
+        &mut self,
+        name: &'static str,
+        help: &'static str,
+        kind: MetricKind,
+        collect: impl Fn() -> Vec<Sample> + 'static,
+    ) {
+        assert!(
+            self.metrics.iter().all(|metric| metric.name != name),
+            "BUG: metric {} registered twice",
+            name
+        );
+        self.metrics.push(RegisteredMetric {
+            name,
+            help,
+            kind,
+            collect: Box::new(collect),
+        });
+    }
+
+    /// Reads the current samples of all registered metrics.
+    pub fn collect(&self) -> Vec<MetricFamily> {
+        self.metrics
+            .iter()
+            .map(|metric| MetricFamily {
+                name: metric.name.to_string(),
+                help: metric.help.to_string(),
+                kind: metric.kind,
+                samples: (metric.collect)(),
+            })
+            .collect()
+    }
+
+    /// Encodes all registered metrics in the Prometheus text format.
+    pub fn encode(&self, w: &mut MetricsEncoder<Vec<u8>>) -> std::io::Result<()> {
+        encode_prometheus(&self.collect(), w)
+    }
+}
+
+/// Encodes the metrics in the Prometheus text format.
+pub fn encode_prometheus(
+    families: &[MetricFamily],
+    w: &mut MetricsEncoder<Vec<u8>>,
+) -> std::io::Result<()> {
+    for family in families {
+        match family.kind {
+            MetricKind::Counter | MetricKind::Gauge => match family.samples.as_slice() {
+                // metrics without labels are encoded as `name value` instead of `name{} value`
+                [Sample {
+                    labels,
+                    value: SampleValue::Value(value),
+                }] if labels.is_empty() => {
+                    if family.kind == MetricKind::Counter {
+                        w.encode_counter(&family.name, *value, &family.help)?;
+                    } else {
+                        w.encode_gauge(&family.name, *value, &family.help)?;
+                    }
+                }
+                samples => {
+                    let mut builder = if family.kind == MetricKind::Counter {
+                        w.counter_vec(&family.name, &family.help)?
+                    } else {
+                        w.gauge_vec(&family.name, &family.help)?
+                    };
+                    for sample in samples {
+                        if let SampleValue::Value(value) = sample.value {
+                            builder = builder.value(&label_refs(&sample.labels), value)?;
+                        }
+                    }
+                }
+            },
+            MetricKind::Histogram => {
+                let mut builder = w.histogram_vec(&family.name, &family.help)?;
+                for sample in &family.samples {
+                    if let SampleValue::Histogram(histogram) = &sample.value {
+                        builder = builder.histogram(
+                            &label_refs(&sample.labels),
+                            histogram.buckets.iter().copied(),
+                            histogram.sum,
+                        )?;
+                    }
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+fn scalar_samples<V: MetricValue>(values: Vec<(Labels, V)>) -> Vec<Sample> {
+    values
+        .into_iter()
+        .map(|(labels, value)| Sample {
+            labels: owned_labels(labels),
+            value: SampleValue::Value(value.metric_value()),
+        })
+        .collect()
+}
+
+fn owned_labels(labels: Labels) -> Vec<(String, String)> {
+    labels
+        .into_iter()
+        .map(|(name, value)| (name.to_string(), value))
+        .collect()
+}
+
+fn label_refs(labels: &[(String, String)]) -> Vec<(&str, &str)> {
+    labels
+        .iter()
+        .map(|(name, value)| (name.as_str(), value.as_str()))
+        .collect()
+}
+
+thread_local! {
+    static REGISTRY: MetricsRegistry = {
+        let mut registry = MetricsRegistry::default();
+        register_system_metrics(&mut registry);
+        register_app_metrics(&mut registry);
+        registry
+    };
+}
 
 pub fn encode_metrics(w: &mut MetricsEncoder<Vec<u8>>) -> std::io::Result<()> {
-    const WASM_PAGE_SIZE_IN_BYTES: f64 = 65536.0;
-
-    w.gauge_vec("cycle_balance", "Cycle balance of this canister")?
-        .value(
-            &[("canister", "backend")],
-            ic_cdk::api::canister_balance128().metric_value(),
-        )?;
-    w.encode_gauge(
-        "canister_version",
-        ic_cdk::api::canister_version().metric_value(),
-        "Canister version",
-    )?;
-    w.encode_gauge(
+    encode_prometheus(&collect_metrics(), w)
+}
+
+/// Returns the current samples of the metrics encoded by [`encode_metrics`].
+pub fn collect_metrics() -> Vec<MetricFamily> {
+    REGISTRY.with(|registry| registry.collect())
+}
+
+/// Registers the metrics of the canister that are read from system APIs.
+fn register_system_metrics(registry: &mut MetricsRegistry) {
+    registry.gauge_vec("cycle_balance", "Cycle balance of this canister", || {
+        vec![(
+            vec![("canister", "backend".to_string())],
+            ic_cdk::api::canister_balance128(),
+        )]
+    });
+    registry.gauge("canister_version", "Canister version", || {
+        ic_cdk::api::canister_version()
+    });
+    registry.gauge(
         "stable_memory_bytes",
-        ic_cdk::api::stable::stable_size() as f64 * WASM_PAGE_SIZE_IN_BYTES,
         "Size of the stable memory allocated by this canister.",
-    )?;
-
-    w.encode_gauge(
+        stable_memory_size_bytes,
+    );
+    registry.gauge(
         "heap_memory_bytes",
-        heap_memory_size_bytes() as f64,
         "Size of the heap memory allocated by this canister.",
-    )?;
+        heap_memory_size_bytes,
+    );
+    endpoints::register_metrics(registry);
+    history::register_metrics(registry);
+}
 
-    Ok(())
+/// Registers the metrics of the application, which only depend on the state
+/// of the canister and not on system APIs.
+pub fn register_app_metrics(registry: &mut MetricsRegistry) {
+    registry.counter(
+        "greetings_total",
+        "Number of calls to greet, including those of forgotten names.",
+        || read_state(|s| s.total_greetings),
+    );
+    registry.gauge(
+        "retained_greetings",
+        "Number of calls to greet with names that were not forgotten.",
+        || read_state(|s| s.greeted_names_count.values().sum::<u64>()),
+    );
+    registry.gauge(
+        "greeted_names",
+        "Number of distinct names that were greeted.",
+        || read_state(|s| s.greeted_names_count.len()),
+    );
+    registry.gauge(
+        "last_replay_instructions",
+        "Number of instructions it took to replay the event log during the last upgrade.",
+        || read_state(|s| s.last_replay_instructions),
+    );
+    registry.gauge(
+        "event_log_length",
+        "Number of events in the event log.",
+        total_event_count,
+    );
+    crate::logs::register_metrics(registry);
+}
+
+/// Returns the amount of stable memory in bytes that has been allocated.
+pub fn stable_memory_size_bytes() -> u64 {
+    const WASM_PAGE_SIZE_IN_BYTES: u64 = 65536;
+    ic_cdk::api::stable::stable_size() * WASM_PAGE_SIZE_IN_BYTES
 }
 
 /// Returns the amount of heap memory in bytes that has been allocated.
@@ -41,3 +333,104 @@ pub fn heap_memory_size_bytes() -> usize {
 pub fn heap_memory_size_bytes() -> usize {
     0
 }
+
+#[cfg(test)]
+mod tests {
+    use crate::lifecycle::InitArg;
+    use crate::metrics::{register_app_metrics, MetricsRegistry, SampleValue};
+    use crate::state::audit::process_event;
+    use crate::state::event::Event;
+    use crate::state::{initialize_state, mutate_state, State};
+    use crate::storage::log_buffer::set_log_capacity;
+    use ic_metrics_encoder::MetricsEncoder;
+    use prometheus_parse::{Scrape, Value};
+
+    fn app_metrics_registry() -> MetricsRegistry {
+        initialize_state(
+            State::try_from(InitArg {
+                greeting: "Hello".to_string(),
+                ..Default::default()
+            })
+            .unwrap(),
+        );
+        for name in ["alice", "bob", "alice"] {
+            mutate_state(|s| process_event(s, Event::Greeted(name.to_string())));
+        }
+        mutate_state(|s| process_event(s, Event::Forgotten("bob".to_string())));
+        set_log_capacity(10);
+
+        let mut registry = MetricsRegistry::default();
+        register_app_metrics(&mut registry);
+        registry
+    }
+
+    fn scrape(registry: &MetricsRegistry) -> Scrape {
+        let mut encoder = MetricsEncoder::new(vec![], 0);
+        registry.encode(&mut encoder).unwrap();
+        let output = String::from_utf8(encoder.into_inner()).unwrap();
+        Scrape::parse(output.lines().map(|line| Ok(line.to_string()))).unwrap()
+    }
+
+    #[test]
+    fn should_encode_parsable_app_metrics() {
+        let scrape = scrape(&app_metrics_registry());
+
+        let value = |name: &str, priority: Option<&str>| -> f64 {
+            let sample = scrape
+                .samples
+                .iter()
+                .find(|sample| {
+                    sample.metric == name
+                        && priority
+                            .is_none_or(|priority| sample.labels.get("priority") == Some(priority))
+                })
+                .unwrap_or_else(|| panic!("missing metric {}", name));
+            match sample.value {
+                Value::Counter(value) | Value::Gauge(value) | Value::Untyped(value) => value,
+                _ => panic!("unexpected type of metric {}", name),
+            }
+        };
+        assert_eq!(value("greetings_total", None), 3.0);
+        assert_eq!(value("retained_greetings", None), 2.0);
+        assert_eq!(value("greeted_names", None), 1.0);
+        assert_eq!(value("event_log_length", None), 4.0);
+        assert_eq!(value("last_replay_instructions", None), 0.0);
+        assert_eq!(value("log_buffer_capacity", None), 10.0);
+        assert_eq!(value("log_buffer_entries", Some("info")), 0.0);
+        assert_eq!(value("log_evicted_entries_total", Some("debug")), 0.0);
+        assert_eq!(value("log_suppressed_entries_total", Some("trace")), 0.0);
+    }
+
+    #[test]
+    fn should_collect_the_encoded_values() {
+        let registry = app_metrics_registry();
+        let scrape = scrape(&registry);
+
+        let families = registry.collect();
+        assert!(!families.is_empty());
+        for family in families {
+            for sample in family.samples {
+                let SampleValue::Value(value) = sample.value else {
+                    continue;
+                };
+                let encoded = scrape
+                    .samples
+                    .iter()
+                    .find(|encoded| {
+                        encoded.metric == family.name
+                            && sample
+                                .labels
+                                .iter()
+                                .all(|(name, value)| encoded.labels.get(name) == Some(value))
+                    })
+                    .unwrap_or_else(|| panic!("missing metric {}", family.name));
+                match encoded.value {
+                    Value::Counter(encoded) | Value::Gauge(encoded) => {
+                        assert_eq!(encoded, value, "value of {}", family.name)
+                    }
+                    _ => panic!("unexpected type of metric {}", family.name),
+                }
+            }
+        }
+    }
+}
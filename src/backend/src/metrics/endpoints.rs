@@ -0,0 +1,157 @@
+//! Calls, rejections and instructions per canister method.
+//!
+//! The metrics are kept on the heap and reset on upgrade. Methods reject
+//! invalid calls in their guards rather than trapping, as a rejection by a
+//! guard keeps the state changes of the call while a trap rolls them back.
+//! Query calls are only recorded when executed in replicated mode, as the
+//! state changes of query calls are discarded otherwise.
+use crate::metrics::{Histogram, Labels, MetricsRegistry};
+use std::cell::RefCell;
+use std::collections::BTreeMap;
+
+/// The upper bounds of the buckets of the instructions histograms.
+const INSTRUCTION_BUCKETS: [f64; 7] = [1e4, 1e5, 1e6, 1e7, 1e8, 1e9, 1e10];
+
+#[derive(Clone, Debug, Default, PartialEq)]
+struct MethodMetrics {
+    calls: u64,
+    rejections: u64,
+    /// The number of calls per bucket, the last one being `+Inf`.
+    instruction_buckets: [u64; INSTRUCTION_BUCKETS.len() + 1],
+    instructions_sum: f64,
+}
+
+thread_local! {
+    static METHODS: RefCell<BTreeMap<String, MethodMetrics>> = RefCell::default();
+}
+
+/// Records a call of the method that executed the given number of instructions.
+pub fn record_call(method: &str, instructions: u64) {
+    METHODS.with_borrow_mut(|methods| {
+        let metrics = methods.entry(method.to_string()).or_default();
+        metrics.calls += 1;
+        let bucket = INSTRUCTION_BUCKETS
+            .iter()
+            .position(|bound| instructions as f64 <= *bound)
+            .unwrap_or(INSTRUCTION_BUCKETS.len());
+        metrics.instruction_buckets[bucket] += 1;
+        metrics.instructions_sum += instructions as f64;
+    })
+}
+
+/// Records a call of the method that was rejected by its guard.
+pub fn record_rejection(method: &str) {
+    METHODS
+        .with_borrow_mut(|methods| methods.entry(method.to_string()).or_default().rejections += 1)
+}
+
+/// Records a rejection if the guard of the method rejected the call.
+pub fn record_guard_rejection(method: &str, result: Result<(), String>) -> Result<(), String> {
+    if result.is_err() {
+        record_rejection(method);
+    }
+    result
+}
+
+/// Records the call of a method when dropped at the end of the method.
+pub struct CallObserver(&'static str);
+
+/// Starts observing a call of the given method.
+pub fn observe_call(method: &'static str) -> CallObserver {
+    CallObserver(method)
+}
+
+impl Drop for CallObserver {
+    fn drop(&mut self) {
+        record_call(self.0, ic_cdk::api::performance_counter(0));
+    }
+}
+
+/// Returns the number of successful calls per method since the last upgrade.
+pub fn method_calls() -> BTreeMap<String, u64> {
+    METHODS.with_borrow(|methods| {
+        methods
+            .iter()
+            .map(|(method, metrics)| (method.clone(), metrics.calls))
+            .collect()
+    })
+}
+
+/// Registers the metrics of all canister methods that were called.
+pub fn register_metrics(registry: &mut MetricsRegistry) {
+    registry.counter_vec(
+        "canister_method_calls_total",
+        "Number of successful calls per canister method since the last upgrade.",
+        || per_method(|metrics| metrics.calls),
+    );
+    registry.counter_vec(
+        "canister_method_rejections_total",
+        "Number of calls per canister method rejected by its guard since the last upgrade.",
+        || per_method(|metrics| metrics.rejections),
+    );
+    registry.histogram_vec(
+        "canister_method_instructions",
+        "Number of instructions executed by successful calls per canister method.",
+        || {
+            per_method(|metrics| Histogram {
+                buckets: INSTRUCTION_BUCKETS
+                    .iter()
+                    .copied()
+                    .chain(std::iter::once(f64::INFINITY))
+                    .zip(
+                        metrics
+                            .instruction_buckets
+                            .iter()
+                            .map(|count| *count as f64),
+                    )
+                    .collect(),
+                sum: metrics.instructions_sum,
+            })
+        },
+    );
+}
+
+fn per_method<V>(value: impl Fn(&MethodMetrics) -> V) -> Vec<(Labels, V)> {
+    METHODS.with_borrow(|methods| {
+        methods
+            .iter()
+            .map(|(method, metrics)| (vec![("method", method.clone())], value(metrics)))
+            .collect()
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::metrics::endpoints::{record_call, record_rejection, register_metrics};
+    use crate::metrics::MetricsRegistry;
+    use ic_metrics_encoder::MetricsEncoder;
+
+    #[test]
+    fn should_encode_endpoint_metrics() {
+        record_call("greet", 5_000);
+        record_call("greet", 2_000_000);
+        record_call("greet", 20_000_000_000);
+        record_rejection("forget_name");
+
+        let mut registry = MetricsRegistry::default();
+        register_metrics(&mut registry);
+        let mut encoder = MetricsEncoder::new(vec![], 0);
+        registry.encode(&mut encoder).unwrap();
+        let output = String::from_utf8(encoder.into_inner()).unwrap();
+
+        for line in [
+            "canister_method_calls_total{method=\"greet\"} 3 0",
+            "canister_method_calls_total{method=\"forget_name\"} 0 0",
+            "canister_method_rejections_total{method=\"forget_name\"} 1 0",
+            "canister_method_instructions_bucket{method=\"greet\",le=\"10000\"} 1 0",
+            "canister_method_instructions_bucket{method=\"greet\",le=\"1000000\"} 1 0",
+            "canister_method_instructions_bucket{method=\"greet\",le=\"10000000\"} 2 0",
+            "canister_method_instructions_bucket{method=\"greet\",le=\"10000000000\"} 2 0",
+            "canister_method_instructions_bucket{method=\"greet\",le=\"+Inf\"} 3 0",
+            "canister_method_instructions_sum{method=\"greet\"} 20002005000 0",
+            "canister_method_instructions_count{method=\"greet\"} 3 0",
+        ] {
+            assert!(output.contains(line), "missing {} in {}", line, output);
+        }
+    }
+}
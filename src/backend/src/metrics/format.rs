@@ -0,0 +1,337 @@
+//! The encodings of the `/metrics` endpoint, negotiated via the `format`
+//! query parameter or the `Accept` header.
+use crate::metrics::{encode_prometheus, MetricFamily, MetricKind, SampleValue};
+use ic_metrics_encoder::MetricsEncoder;
+use std::fmt::Write;
+use std::str::FromStr;
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum MetricsFormat {
+    /// The Prometheus text format, version 0.0.4.
+    Prometheus,
+    /// The OpenMetrics text format, version 1.0.0.
+    OpenMetrics,
+    /// A flat JSON object mapping each series, e.g.
+    /// `log_buffer_entries{priority="info"}`, to its value.
+    Json,
+}
+
+impl MetricsFormat {
+    pub fn content_type(&self) -> &'static str {
+        match self {
+            MetricsFormat::Prometheus => "text/plain; version=0.0.4",
+            MetricsFormat::OpenMetrics => {
+                "application/openmetrics-text; version=1.0.0; charset=utf-8"
+            }
+            MetricsFormat::Json => "application/json; charset=utf-8",
+        }
+    }
+
+    /// Returns the format set by the `format` query parameter if any, or else
+    /// the supported media type of the `Accept` header with the highest
+    /// quality, defaulting to Prometheus.
+    pub fn negotiate(format: Option<&str>, accept: Option<&str>) -> Result<Self, String> {
+        match format {
+            Some(format) => MetricsFormat::from_str(format),
+            None => Ok(accept
+                .and_then(MetricsFormat::from_accept)
+                .unwrap_or(MetricsFormat::Prometheus)),
+        }
+    }
+
+    fn from_accept(accept: &str) -> Option<Self> {
+        let mut media_ranges: Vec<(f64, Option<MetricsFormat>)> = accept
+            .split(',')
+            .map(|media_range| {
+                let mut params = media_range.split(';').map(str::trim);
+                let format = match params.next().unwrap_or_default().to_lowercase().as_str() {
+                    "application/openmetrics-text" => Some(MetricsFormat::OpenMetrics),
+                    "application/json" => Some(MetricsFormat::Json),
+                    "text/plain" => Some(MetricsFormat::Prometheus),
+                    _ => None,
+                };
+                let quality = params
+                    .find_map(|param| param.strip_prefix("q="))
+                    .and_then(|quality| quality.parse().ok())
+                    .unwrap_or(1.0);
+                (quality, format)
+            })
+            .collect();
+        // the sort is stable, so media ranges of equal quality keep their order
+        media_ranges.sort_by(|(a, _), (b, _)| b.total_cmp(a));
+        media_ranges
+            .into_iter()
+            .filter(|(quality, _)| *quality > 0.0)
+            .find_map(|(_, format)| format)
+    }
+
+    /// Encodes the metrics collected at `now_millis`.
+    pub fn encode(&self, families: &[MetricFamily], now_millis: i64) -> std::io::Result<Vec<u8>> {
+        match self {
+            MetricsFormat::Prometheus => {
+                let mut encoder = MetricsEncoder::new(vec![], now_millis);
+                encode_prometheus(families, &mut encoder)?;
+                Ok(encoder.into_inner())
+            }
+            MetricsFormat::OpenMetrics => Ok(encode_openmetrics(families, now_millis).into_bytes()),
+            MetricsFormat::Json => Ok(encode_json(families).into_bytes()),
+        }
+    }
+}
+
+impl FromStr for MetricsFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "prometheus" => Ok(MetricsFormat::Prometheus),
+            "openmetrics" => Ok(MetricsFormat::OpenMetrics),
+            "json" => Ok(MetricsFormat::Json),
+            _ => Err("could not recognize format".to_string()),
+        }
+    }
+}
+
+/// Formats a value like Go's `ParseFloat` expects it, as required by both
+/// text formats.
+fn format_value(value: f64) -> String {
+    if value.is_nan() {
+        "NaN".to_string()
+    } else if value == f64::INFINITY {
+        "+Inf".to_string()
+    } else if value == f64::NEG_INFINITY {
+        "-Inf".to_string()
+    } else {
+        value.to_string()
+    }
+}
+
+/// Returns the name of a series with its labels, e.g. `calls{method="greet"}`.
+fn series(name: &str, labels: &[(String, String)], le: Option<f64>) -> String {
+    let mut labels: Vec<String> = labels
+        .iter()
+        .map(|(name, value)| format!("{}=\"{}\"", name, escape_label_value(value)))
+        .collect();
+    if let Some(le) = le {
+        labels.push(format!("le=\"{}\"", format_value(le)));
+    }
+    if labels.is_empty() {
+        name.to_string()
+    } else {
+        format!("{}{{{}}}", name, labels.join(","))
+    }
+}
+
+fn escape_label_value(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+/// Returns each series of the metrics with its value, histograms being
+/// expanded into cumulative `_bucket` series and `_sum` and `_count`.
+fn series_values(family: &MetricFamily) -> Vec<(String, f64)> {
+    let name = match family.kind {
+        MetricKind::Counter => format!("{}_total", counter_name(family)),
+        MetricKind::Gauge | MetricKind::Histogram => family.name.clone(),
+    };
+    let mut values = vec![];
+    for sample in &family.samples {
+        match &sample.value {
+            SampleValue::Value(value) => values.push((series(&name, &sample.labels, None), *value)),
+            SampleValue::Histogram(histogram) => {
+                let mut count = 0.0;
+                for (le, bucket_count) in &histogram.buckets {
+                    count += bucket_count;
+                    values.push((
+                        series(&format!("{}_bucket", name), &sample.labels, Some(*le)),
+                        count,
+                    ));
+                }
+                values.push((
+                    series(&format!("{}_sum", name), &sample.labels, None),
+                    histogram.sum,
+                ));
+                values.push((
+                    series(&format!("{}_count", name), &sample.labels, None),
+                    count,
+                ));
+            }
+        }
+    }
+    values
+}
+
+/// OpenMetrics names counters without the `_total` suffix of their samples.
+fn counter_name(family: &MetricFamily) -> &str {
+    family
+        .name
+        .strip_suffix("_total")
+        .unwrap_or(family.name.as_str())
+}
+
+// Writing to a `String` cannot fail.
+fn encode_openmetrics(families: &[MetricFamily], now_millis: i64) -> String {
+    // OpenMetrics timestamps are in seconds
+    let timestamp = format_value(now_millis as f64 / 1_000.0);
+    let mut out = String::new();
+    for family in families {
+        let (name, kind) = match family.kind {
+            MetricKind::Counter => (counter_name(family), "counter"),
+            MetricKind::Gauge => (family.name.as_str(), "gauge"),
+            MetricKind::Histogram => (family.name.as_str(), "histogram"),
+        };
+        writeln!(out, "# TYPE {} {}", name, kind).unwrap();
+        writeln!(
+            out,
+            "# HELP {} {}",
+            name,
+            family.help.replace('\\', "\\\\").replace('\n', "\\n")
+        )
+        .unwrap();
+        for (series, value) in series_values(family) {
+            writeln!(out, "{} {} {}", series, format_value(value), timestamp).unwrap();
+        }
+    }
+    out.push_str("# EOF\n");
+    out
+}
+
+/// Values that are not finite are encoded as `null`.
+fn encode_json(families: &[MetricFamily]) -> String {
+    let object: serde_json::Map<String, serde_json::Value> = families
+        .iter()
+        .flat_map(series_values)
+        .map(|(series, value)| (series, serde_json::Value::from(value)))
+        .collect();
+    serde_json::Value::Object(object).to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::metrics::format::MetricsFormat;
+    use crate::metrics::{Histogram, MetricFamily, MetricKind, Sample, SampleValue};
+    use prometheus_parse::{Scrape, Value};
+
+    const PROMETHEUS_ACCEPT: &str = "application/openmetrics-text;version=1.0.0,application/openmetrics-text;version=0.0.1;q=0.75,text/plain;version=0.0.4;q=0.5,*/*;q=0.1";
+
+    fn families() -> Vec<MetricFamily> {
+        vec![
+            MetricFamily {
+                name: "greetings_total".to_string(),
+                help: "Number of greetings.".to_string(),
+                kind: MetricKind::Counter,
+                samples: vec![Sample {
+                    labels: vec![],
+                    value: SampleValue::Value(3.0),
+                }],
+            },
+            MetricFamily {
+                name: "log_buffer_entries".to_string(),
+                help: "Number of log entries.".to_string(),
+                kind: MetricKind::Gauge,
+                samples: vec![Sample {
+                    labels: vec![("priority".to_string(), "info".to_string())],
+                    value: SampleValue::Value(2.0),
+                }],
+            },
+            MetricFamily {
+                name: "instructions".to_string(),
+                help: "Number of instructions.".to_string(),
+                kind: MetricKind::Histogram,
+                samples: vec![Sample {
+                    labels: vec![("method".to_string(), "greet".to_string())],
+                    value: SampleValue::Histogram(Histogram {
+                        buckets: vec![(1e4, 1.0), (f64::INFINITY, 2.0)],
+                        sum: 50_000.5,
+                    }),
+                }],
+            },
+        ]
+    }
+
+    fn encode(format: MetricsFormat) -> String {
+        String::from_utf8(format.encode(&families(), 1_500).unwrap()).unwrap()
+    }
+
+    #[test]
+    fn should_negotiate_format() {
+        let negotiate = MetricsFormat::negotiate;
+        assert_eq!(negotiate(None, None), Ok(MetricsFormat::Prometheus));
+        assert_eq!(
+            negotiate(None, Some(PROMETHEUS_ACCEPT)),
+            Ok(MetricsFormat::OpenMetrics)
+        );
+        assert_eq!(
+            negotiate(None, Some("application/json")),
+            Ok(MetricsFormat::Json)
+        );
+        assert_eq!(
+            negotiate(None, Some("application/json;q=0.5, text/plain")),
+            Ok(MetricsFormat::Prometheus)
+        );
+        assert_eq!(
+            negotiate(None, Some("application/json;q=0, text/html")),
+            Ok(MetricsFormat::Prometheus)
+        );
+        assert_eq!(
+            negotiate(Some("json"), Some(PROMETHEUS_ACCEPT)),
+            Ok(MetricsFormat::Json)
+        );
+        assert!(negotiate(Some("xml"), None).is_err());
+    }
+
+    #[test]
+    fn should_encode_prometheus() {
+        let output = encode(MetricsFormat::Prometheus);
+        let scrape = Scrape::parse(output.lines().map(|line| Ok(line.to_string()))).unwrap();
+        let value = |name: &str| {
+            scrape
+                .samples
+                .iter()
+                .find(|sample| sample.metric == name)
+                .map(|sample| sample.value.clone())
+        };
+        assert_eq!(value("greetings_total"), Some(Value::Counter(3.0)));
+        assert_eq!(value("log_buffer_entries"), Some(Value::Gauge(2.0)));
+        assert!(matches!(value("instructions"), Some(Value::Histogram(_))));
+    }
+
+    #[test]
+    fn should_encode_openmetrics() {
+        assert_eq!(
+            encode(MetricsFormat::OpenMetrics),
+            r#"# TYPE greetings counter
+# HELP greetings Number of greetings.
+greetings_total 3 1.5
+# TYPE log_buffer_entries gauge
+# HELP log_buffer_entries Number of log entries.
+log_buffer_entries{priority="info"} 2 1.5
+# TYPE instructions histogram
+# HELP instructions Number of instructions.
+instructions_bucket{method="greet",le="10000"} 1 1.5
+instructions_bucket{method="greet",le="+Inf"} 3 1.5
+instructions_sum{method="greet"} 50000.5 1.5
+instructions_count{method="greet"} 3 1.5
+# EOF
+"#
+        );
+    }
+
+    #[test]
+    fn should_encode_json() {
+        let output: serde_json::Value = serde_json::from_str(&encode(MetricsFormat::Json)).unwrap();
+        assert_eq!(
+            output,
+            serde_json::json!({
+                "greetings_total": 3.0,
+                "log_buffer_entries{priority=\"info\"}": 2.0,
+                "instructions_bucket{method=\"greet\",le=\"10000\"}": 1.0,
+                "instructions_bucket{method=\"greet\",le=\"+Inf\"}": 3.0,
+                "instructions_sum{method=\"greet\"}": 50000.5,
+                "instructions_count{method=\"greet\"}": 3.0,
+            })
+        );
+    }
+}
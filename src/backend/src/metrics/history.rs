@@ -0,0 +1,238 @@
+//! Periodic sampling of the metrics into the stable history, for environments
+//! from which the canister cannot be scraped.
+use crate::log;
+use crate::logs::{ERROR, INFO};
+use crate::metrics::endpoints::method_calls;
+use crate::metrics::{heap_memory_size_bytes, stable_memory_size_bytes, MetricsRegistry};
+use crate::state::STATE;
+use crate::storage::metrics_history::{
+    metrics_history_since, record_metrics_sample, MetricsSample,
+};
+use ic_cdk::api::management_canister::main::{canister_status, CanisterIdRecord};
+use std::cell::Cell;
+use std::time::Duration;
+
+pub const METRICS_SAMPLING_INTERVAL: Duration = Duration::from_secs(10 * 60);
+/// The period over which the cycles burn rate is averaged.
+pub const BURN_RATE_WINDOW: Duration = Duration::from_secs(24 * 60 * 60);
+/// The default freezing threshold of canisters.
+const DEFAULT_FREEZING_THRESHOLD: Duration = Duration::from_secs(30 * 24 * 60 * 60);
+/// The fee in cycles for storing a GiB for a second on a subnet of 13 nodes.
+const STORAGE_FEE_PER_GIB_SECOND: f64 = 127_000.0;
+
+thread_local! {
+    /// The cycles reserved by the freezing threshold as reported by
+    /// `canister_status`, which requires the canister to be its own controller.
+    static REPORTED_FREEZING_RESERVE: Cell<Option<u128>> = const { Cell::new(None) };
+
+    /// Whether the last sample reported low cycles.
+    static LOW_CYCLES: Cell<bool> = const { Cell::new(false) };
+}
+
+/// Records a sample now and then every [`METRICS_SAMPLING_INTERVAL`]. Timers
+/// do not survive upgrades, so this has to be called after every upgrade.
+pub fn start_sampling() {
+    ic_cdk_timers::set_timer(Duration::ZERO, sample_metrics);
+    ic_cdk_timers::set_timer_interval(METRICS_SAMPLING_INTERVAL, sample_metrics);
+}
+
+fn sample_metrics() {
+    record_metrics_sample(MetricsSample {
+        timestamp: ic_cdk::api::time(),
+        cycle_balance: ic_cdk::api::canister_balance128(),
+        stable_memory_bytes: stable_memory_size_bytes(),
+        heap_memory_bytes: heap_memory_size_bytes() as u64,
+        method_calls: method_calls(),
+    });
+
+    refresh_freezing_reserve();
+    report_low_cycles();
+}
+
+/// Fetches the freezing reserve from `canister_status` if the canister is
+/// its own controller, and otherwise keeps estimating it.
+fn refresh_freezing_reserve() {
+    let canister_id = ic_cdk::id();
+    if !ic_cdk::api::is_controller(&canister_id) {
+        return;
+    }
+    ic_cdk::spawn(async move {
+        if let Ok((status,)) = canister_status(CanisterIdRecord { canister_id }).await {
+            let reserve =
+                status.idle_cycles_burned_per_day * status.settings.freezing_threshold / 86_400_u64;
+            REPORTED_FREEZING_RESERVE.set(u128::try_from(reserve.0).ok());
+        }
+    });
+}
+
+/// Logs an error when the estimated time until the freezing threshold drops
+/// below the configured threshold, and once it recovers.
+fn report_low_cycles() {
+    // the state is not initialized while a stable memory migration is pending
+    let Some(threshold) = STATE.with_borrow(|s| {
+        s.as_ref()
+            .map(|s| Duration::from_secs(s.low_cycles_threshold_seconds))
+    }) else {
+        return;
+    };
+    let runway = cycles_runway();
+    let low_cycles = runway.is_some_and(|runway| runway < threshold);
+    if LOW_CYCLES.replace(low_cycles) == low_cycles {
+        return;
+    }
+    match runway {
+        Some(runway) if low_cycles => log!(
+            ERROR,
+            "[cycles]: the freezing threshold is estimated to be reached in {} seconds, below the threshold of {} seconds",
+            runway.as_secs(),
+            threshold.as_secs()
+        ),
+        _ => log!(
+            INFO,
+            "[cycles]: the freezing threshold is no longer estimated to be reached within {} seconds",
+            threshold.as_secs()
+        ),
+    }
+}
+
+/// Returns the average number of cycles burned per second between the first
+/// and the last sample. Increases of the balance, e.g. top-ups, are ignored.
+pub fn cycles_burn_rate(samples: &[MetricsSample]) -> Option<f64> {
+    let (first, last) = (samples.first()?, samples.last()?);
+    let elapsed = last.timestamp.saturating_sub(first.timestamp);
+    if elapsed == 0 {
+        return None;
+    }
+    let burned: u128 = samples
+        .windows(2)
+        .map(|pair| pair[0].cycle_balance.saturating_sub(pair[1].cycle_balance))
+        .sum();
+    Some(burned as f64 / Duration::from_nanos(elapsed).as_secs_f64())
+}
+
+/// Estimates the time until the balance drops below the freezing reserve
+/// when burning cycles at the given rate.
+pub fn time_until_frozen(balance: u128, burn_rate: f64, freezing_reserve: f64) -> Option<Duration> {
+    if burn_rate <= 0.0 {
+        return None;
+    }
+    let runway = (balance as f64 - freezing_reserve) / burn_rate;
+    Some(Duration::try_from_secs_f64(runway.max(0.0)).unwrap_or(Duration::MAX))
+}
+
+/// Estimates the cycles reserved by the default freezing threshold from the
+/// storage fees of the memory of the canister, which dominate its idle burn.
+pub fn estimated_freezing_reserve(memory_bytes: u64) -> f64 {
+    const GIB: f64 = (1_u64 << 30) as f64;
+    memory_bytes as f64 / GIB
+        * STORAGE_FEE_PER_GIB_SECOND
+        * DEFAULT_FREEZING_THRESHOLD.as_secs_f64()
+}
+
+/// Returns the cycles reserved by the freezing threshold, as reported by
+/// `canister_status` or else estimated.
+fn freezing_reserve() -> f64 {
+    REPORTED_FREEZING_RESERVE
+        .get()
+        .map(|reserve| reserve as f64)
+        .unwrap_or_else(|| {
+            estimated_freezing_reserve(stable_memory_size_bytes() + heap_memory_size_bytes() as u64)
+        })
+}
+
+/// Returns the cycles burn rate over the last [`BURN_RATE_WINDOW`].
+pub fn recent_cycles_burn_rate() -> Option<f64> {
+    let since = ic_cdk::api::time().saturating_sub(BURN_RATE_WINDOW.as_nanos() as u64);
+    cycles_burn_rate(&metrics_history_since(since, usize::MAX))
+}
+
+/// Returns the estimated time until the freezing threshold is reached, if
+/// cycles were burned recently.
+pub fn cycles_runway() -> Option<Duration> {
+    time_until_frozen(
+        ic_cdk::api::canister_balance128(),
+        recent_cycles_burn_rate()?,
+        freezing_reserve(),
+    )
+}
+
+/// Registers the metrics estimated from the history.
+pub fn register_metrics(registry: &mut MetricsRegistry) {
+    registry.gauge_vec(
+        "cycles_burn_rate",
+        "Number of cycles burned per second, averaged over the last day.",
+        || {
+            recent_cycles_burn_rate()
+                .map(|rate| (vec![], rate))
+                .into_iter()
+                .collect()
+        },
+    );
+    registry.gauge_vec(
+        "cycles_runway_seconds",
+        "Estimated time in seconds until the cycle balance drops below the freezing threshold.",
+        || {
+            cycles_runway()
+                .map(|runway| (vec![], runway))
+                .into_iter()
+                .collect()
+        },
+    );
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::metrics::history::{
+        cycles_burn_rate, estimated_freezing_reserve, time_until_frozen,
+    };
+    use crate::storage::metrics_history::MetricsSample;
+    use std::time::Duration;
+
+    fn sample(seconds: u64, cycle_balance: u128) -> MetricsSample {
+        MetricsSample {
+            timestamp: seconds * 1_000_000_000,
+            cycle_balance,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn should_compute_burn_rate_ignoring_top_ups() {
+        assert_eq!(cycles_burn_rate(&[]), None);
+        assert_eq!(cycles_burn_rate(&[sample(0, 1_000)]), None);
+        assert_eq!(
+            cycles_burn_rate(&[sample(0, 1_000), sample(10, 900)]),
+            Some(10.0)
+        );
+        assert_eq!(
+            cycles_burn_rate(&[
+                sample(0, 1_000),
+                sample(10, 900),
+                sample(20, 5_000),
+                sample(40, 4_800)
+            ]),
+            Some(7.5)
+        );
+    }
+
+    #[test]
+    fn should_estimate_time_until_frozen() {
+        assert_eq!(time_until_frozen(1_000, 0.0, 0.0), None);
+        assert_eq!(
+            time_until_frozen(8_000, 2.0, 800.0),
+            Some(Duration::from_secs(3_600))
+        );
+        assert_eq!(time_until_frozen(800, 2.0, 1_000.0), Some(Duration::ZERO));
+        assert_eq!(time_until_frozen(u128::MAX, 1e-9, 0.0), Some(Duration::MAX));
+    }
+
+    #[test]
+    fn should_estimate_freezing_reserve_from_memory() {
+        // 1 GiB for 30 days
+        assert_eq!(
+            estimated_freezing_reserve(1 << 30),
+            127_000.0 * 30.0 * 24.0 * 3_600.0
+        );
+        assert_eq!(estimated_freezing_reserve(0), 0.0);
+    }
+}
@@ -1,5 +1,15 @@
-use std::{cell::RefCell, collections::HashMap};
+use candid::{CandidType, Principal};
+use serde::Deserialize;
+use std::{
+    cell::RefCell,
+    collections::{BTreeSet, HashMap},
+    fmt,
+};
+
+use crate::lifecycle::Arg;
+
 pub mod audit;
+pub mod event;
 
 thread_local! {
     pub static STATE: RefCell<Option<State>> = RefCell::default();
@@ -8,22 +18,125 @@ thread_local! {
 #[derive(Clone, PartialEq, Debug)]
 pub struct State {
     pub greeting: String,
+    /// Principals that are allowed to call admin endpoints in addition to
+    /// the controllers of the canister.
+    pub admins: BTreeSet<Principal>,
+    /// The number of retained log entries per priority.
+    pub log_capacity: u64,
+    /// The maximum size of the body returned by the `/logs` endpoint.
+    pub max_logs_body_size: u64,
+    /// The maximum length in bytes of a name that can be greeted.
+    pub max_name_length: u64,
+    /// The maximum size in bytes of a log message returned by the `/logs` endpoint.
+    pub max_log_message_size: u64,
+    /// The estimated time in seconds until the freezing threshold below which
+    /// low cycles are reported.
+    pub low_cycles_threshold_seconds: u64,
     pub greeted_names_count: HashMap<String, u64>,
+    /// The number of greetings, including those of forgotten names.
+    pub total_greetings: u64,
+    /// The number of instructions it took to replay the event log during the
+    /// last upgrade.
+    pub last_replay_instructions: u64,
+    /// The last [`MAX_UPGRADE_HISTORY_LEN`] installations and upgrades of the
+    /// canister, oldest first.
+    pub upgrade_history: Vec<UpgradeRecord>,
+}
+
+#[derive(Clone, PartialEq, Debug, CandidType, Deserialize)]
+pub struct UpgradeRecord {
+    pub canister_version: u64,
+    pub timestamp: u64,
+    pub arg: Arg,
 }
 
 #[derive(Eq, PartialEq, Debug)]
 pub enum InvalidStateError {
     InvalidGreeting(String),
+    InvalidLogCapacity(String),
+    InvalidMaxLogsBodySize(String),
+    InvalidMaxNameLength(String),
+    InvalidMaxLogMessageSize(String),
+}
+
+/// The maximum number of upgrades kept in the upgrade history.
+pub const MAX_UPGRADE_HISTORY_LEN: usize = 100;
+/// The maximum number of retained log entries per priority.
+pub const MAX_LOG_CAPACITY: u64 = 100_000;
+/// The maximum size of a query response is 3 MiB.
+pub const MAX_LOGS_BODY_SIZE: u64 = 3 * 1024 * 1024;
+
+impl fmt::Display for InvalidStateError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            InvalidStateError::InvalidGreeting(reason) => write!(f, "invalid greeting: {}", reason),
+            InvalidStateError::InvalidLogCapacity(reason) => {
+                write!(f, "invalid log capacity: {}", reason)
+            }
+            InvalidStateError::InvalidMaxLogsBodySize(reason) => {
+                write!(f, "invalid max logs body size: {}", reason)
+            }
+            InvalidStateError::InvalidMaxNameLength(reason) => {
+                write!(f, "invalid max name length: {}", reason)
+            }
+            InvalidStateError::InvalidMaxLogMessageSize(reason) => {
+                write!(f, "invalid max log message size: {}", reason)
+            }
+        }
+    }
 }
 
 impl State {
-    pub fn validate_config(&self) -> Result<(), InvalidStateError> {
+    /// Checks the config of the state and returns all violations.
+    pub fn validate_config(&self) -> Result<(), Vec<InvalidStateError>> {
+        let mut errors = vec![];
         if self.greeting.trim().is_empty() {
-            return Err(InvalidStateError::InvalidGreeting(
+            errors.push(InvalidStateError::InvalidGreeting(
                 "greeting cannot be blank".to_string(),
             ));
         }
-        Ok(())
+        if self.log_capacity == 0 {
+            errors.push(InvalidStateError::InvalidLogCapacity(
+                "log capacity must be positive".to_string(),
+            ));
+        }
+        if self.log_capacity > MAX_LOG_CAPACITY {
+            errors.push(InvalidStateError::InvalidLogCapacity(format!(
+                "log capacity cannot exceed {}",
+                MAX_LOG_CAPACITY
+            )));
+        }
+        if self.max_logs_body_size == 0 {
+            errors.push(InvalidStateError::InvalidMaxLogsBodySize(
+                "max logs body size must be positive".to_string(),
+            ));
+        }
+        if self.max_logs_body_size > MAX_LOGS_BODY_SIZE {
+            errors.push(InvalidStateError::InvalidMaxLogsBodySize(format!(
+                "max logs body size cannot exceed {}",
+                MAX_LOGS_BODY_SIZE
+            )));
+        }
+        if self.max_name_length == 0 {
+            errors.push(InvalidStateError::InvalidMaxNameLength(
+                "max name length must be positive".to_string(),
+            ));
+        }
+        if self.max_log_message_size == 0 {
+            errors.push(InvalidStateError::InvalidMaxLogMessageSize(
+                "max log message size must be positive".to_string(),
+            ));
+        }
+        if self.max_log_message_size > self.max_logs_body_size {
+            errors.push(InvalidStateError::InvalidMaxLogMessageSize(
+                "max log message size cannot exceed the max logs body size".to_string(),
+            ));
+        }
+        if errors.is_empty() {
+            Ok(())
+        } else {
+            Err(errors)
+        }
     }
 }
 
@@ -42,6 +155,12 @@ where
     STATE.with_borrow_mut(|s| f(s.as_mut().expect("BUG: state is not initialized")))
 }
 
+/// Whether the state was initialized. It is not while a stable memory
+/// migration started by an upgrade is in progress.
+pub fn is_state_initialized() -> bool {
+    STATE.with_borrow(Option::is_some)
+}
+
 /// Sets the current state to `state`.
 pub fn initialize_state(state: State) {
     STATE.set(Some(state));
@@ -1,16 +1,313 @@
+use candid::CandidType;
+use serde::Deserialize;
+use std::cell::RefCell;
 use std::collections::HashMap;
 
-use crate::storage::with_event_iter;
+use crate::state::event::Event;
+use crate::state::{State, UpgradeRecord, MAX_UPGRADE_HISTORY_LEN};
+use crate::storage::schema::has_instruction_budget;
+use crate::storage::{
+    finish_rewriting_events, read_event, record_event, record_rewritten_event,
+    start_rewriting_events, total_event_count, with_event_iter, zero_unused_event_log,
+};
 
-pub fn replay_events() -> HashMap<String, u64> {
-    let mut greeted_names_count = HashMap::new();
-    with_event_iter(|events| {
-        for event in events {
-            greeted_names_count
-                .entry(event)
+/// Updates the state to reflect the given event.
+pub fn apply_event(state: &mut State, event: Event) {
+    match event {
+        Event::Greeted(name) => {
+            state.total_greetings += 1;
+            state
+                .greeted_names_count
+                .entry(name)
                 .and_modify(|count| *count += 1)
                 .or_insert(1);
         }
+        Event::Forgotten(name) => {
+            state.greeted_names_count.remove(&name);
+        }
+        Event::Compacted { removed_greetings } => {
+            state.total_greetings += removed_greetings;
+        }
+        Event::Migrated { .. } => {}
+        Event::Upgraded {
+            canister_version,
+            timestamp,
+            arg,
+        } => {
+            state.upgrade_history.push(UpgradeRecord {
+                canister_version,
+                timestamp,
+                arg,
+            });
+            if state.upgrade_history.len() > MAX_UPGRADE_HISTORY_LEN {
+                state.upgrade_history.remove(0);
+            }
+        }
+    }
+}
+
+/// Records the given event in the event log and applies it to the state.
+pub fn process_event(state: &mut State, event: Event) {
+    record_event(&event);
+    apply_event(state, event);
+}
+
+/// Restores the parts of the state that are derived from the event log.
+pub fn replay_events(state: &mut State) {
+    with_event_iter(|events| {
+        for event in events {
+            apply_event(state, event);
+        }
     });
-    greeted_names_count
+}
+
+/// The progress of an event log compaction. It is kept on the heap, so an
+/// upgrade aborts the compaction and the event log stays as it was. Zeroing
+/// the previous log after the switch is persisted in stable memory and
+/// resumed after an upgrade by [`zero_previous_event_log`].
+#[derive(Default)]
+struct Compaction {
+    phase: CompactionPhase,
+    /// The index of the next event to process.
+    cursor: u64,
+    /// The number of events when the compaction started. Events that are
+    /// recorded while compacting are retained.
+    end: u64,
+    /// The index of the last tombstone of each forgotten name.
+    tombstones: HashMap<String, u64>,
+    removed_events: u64,
+    removed_greetings: u64,
+}
+
+#[derive(Default)]
+enum CompactionPhase {
+    /// Collecting the tombstones of the events before `end`.
+    #[default]
+    Scanning,
+    /// Copying the retained events into the rewritten log.
+    Copying,
+    /// Zeroing the memory of the previous log.
+    Zeroing,
+}
+
+impl Compaction {
+    fn retains(&self, index: u64, event: &Event) -> bool {
+        if index >= self.end {
+            return true;
+        }
+        match event {
+            Event::Greeted(name) => self
+                .tombstones
+                .get(name)
+                .is_none_or(|tombstone| *tombstone < index),
+            Event::Forgotten(_) => false,
+            Event::Compacted { .. } | Event::Migrated { .. } | Event::Upgraded { .. } => true,
+        }
+    }
+
+    /// Processes the next event or chunk of memory. Returns `true` once the
+    /// compaction is complete.
+    fn step(&mut self) -> bool {
+        match self.phase {
+            CompactionPhase::Scanning => {
+                if self.cursor == self.end {
+                    // zeroing the log of a previous rewrite may have been
+                    // interrupted by an upgrade
+                    if !zero_unused_event_log(ZEROED_BYTES_PER_STEP) {
+                        return false;
+                    }
+                    start_rewriting_events();
+                    self.phase = CompactionPhase::Copying;
+                    self.cursor = 0;
+                    return false;
+                }
+                if let Some(Event::Forgotten(name)) = read_event(self.cursor) {
+                    self.tombstones.insert(name, self.cursor);
+                }
+                self.cursor += 1;
+            }
+            CompactionPhase::Copying => match read_event(self.cursor) {
+                Some(event) => {
+                    if self.retains(self.cursor, &event) {
+                        record_rewritten_event(&event);
+                    } else {
+                        self.removed_events += 1;
+                        if matches!(event, Event::Greeted(_)) {
+                            self.removed_greetings += 1;
+                        }
+                    }
+                    self.cursor += 1;
+                }
+                None => {
+                    if self.removed_greetings > 0 {
+                        record_rewritten_event(&Event::Compacted {
+                            removed_greetings: self.removed_greetings,
+                        });
+                    }
+                    finish_rewriting_events();
+                    self.phase = CompactionPhase::Zeroing;
+                }
+            },
+            CompactionPhase::Zeroing => return zero_unused_event_log(ZEROED_BYTES_PER_STEP),
+        }
+        false
+    }
+}
+
+/// The number of bytes of the previous log that are zeroed per step.
+const ZEROED_BYTES_PER_STEP: u64 = 1024 * 1024;
+
+thread_local! {
+    static COMPACTION: RefCell<Option<Compaction>> = RefCell::default();
+}
+
+#[derive(Clone, Eq, PartialEq, Debug, CandidType, Deserialize)]
+pub enum CompactionStatus {
+    /// The compaction is complete and removed the given number of events.
+    Completed { removed_events: u64 },
+    /// The compaction has to be resumed in a later message.
+    Pending,
+}
+
+/// Whether a compaction was started and is not complete yet.
+pub fn is_compacting() -> bool {
+    COMPACTION.with_borrow(|compaction| compaction.is_some())
+}
+
+/// Removes all occurrences of forgotten names that precede their tombstone,
+/// as well as the tombstones themselves, from the event log. The number of
+/// removed greetings is recorded in a `Compacted` event.
+///
+/// Starts a compaction or resumes the current one until the instruction
+/// budget of the message is exhausted.
+pub fn compact_events() -> CompactionStatus {
+    run_compaction(has_instruction_budget)
+}
+
+/// Continues zeroing the memory of the event log that was replaced by a
+/// compaction or migration until the instruction budget of the message is
+/// exhausted. Returns `true` once the memory is zeroed.
+pub fn zero_previous_event_log() -> bool {
+    while has_instruction_budget() {
+        if zero_unused_event_log(ZEROED_BYTES_PER_STEP) {
+            return true;
+        }
+    }
+    false
+}
+
+fn run_compaction(mut has_budget: impl FnMut() -> bool) -> CompactionStatus {
+    COMPACTION.with_borrow_mut(|compaction| {
+        let progress = compaction.get_or_insert_with(|| Compaction {
+            end: total_event_count(),
+            ..Default::default()
+        });
+        while has_budget() {
+            if progress.step() {
+                let removed_events = progress.removed_events;
+                *compaction = None;
+                return CompactionStatus::Completed { removed_events };
+            }
+        }
+        CompactionStatus::Pending
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::lifecycle::{Arg, InitArg};
+    use crate::state::audit::{apply_event, replay_events, run_compaction, CompactionStatus};
+    use crate::state::event::Event;
+    use crate::state::{State, MAX_UPGRADE_HISTORY_LEN};
+    use crate::storage::{record_event, total_event_count, with_event_iter};
+
+    fn greeted(name: &str) -> Event {
+        Event::Greeted(name.to_string())
+    }
+
+    fn forgotten(name: &str) -> Event {
+        Event::Forgotten(name.to_string())
+    }
+
+    #[test]
+    fn should_forget_names_and_compact_log() {
+        for event in [
+            greeted("alice"),
+            greeted("bob"),
+            greeted("alice"),
+            forgotten("alice"),
+            greeted("bob"),
+            greeted("alice"),
+        ] {
+            record_event(&event);
+        }
+
+        let replayed_state = || {
+            let mut state = State::try_from(InitArg {
+                greeting: "Hello".to_string(),
+                ..Default::default()
+            })
+            .unwrap();
+            replay_events(&mut state);
+            state
+        };
+        let state = replayed_state();
+        assert_eq!(state.greeted_names_count.get("alice"), Some(&1));
+        assert_eq!(state.greeted_names_count.get("bob"), Some(&2));
+        assert_eq!(state.total_greetings, 5);
+
+        // compact in messages of two steps, greeting in between
+        let mut status = CompactionStatus::Pending;
+        let mut messages = 0;
+        while status == CompactionStatus::Pending {
+            let mut steps = 0;
+            status = run_compaction(|| {
+                steps += 1;
+                steps <= 2
+            });
+            messages += 1;
+            if messages == 3 {
+                record_event(&greeted("carol"));
+            }
+        }
+        assert_eq!(status, CompactionStatus::Completed { removed_events: 3 });
+        assert_eq!(total_event_count(), 5);
+        assert_eq!(
+            with_event_iter(|events| events.collect::<Vec<_>>()),
+            vec![
+                greeted("bob"),
+                greeted("bob"),
+                greeted("alice"),
+                greeted("carol"),
+                Event::Compacted {
+                    removed_greetings: 2
+                }
+            ]
+        );
+        let mut state = state;
+        state.greeted_names_count.insert("carol".to_string(), 1);
+        state.total_greetings += 1;
+        assert_eq!(replayed_state(), state);
+    }
+
+    #[test]
+    fn should_keep_the_last_upgrades() {
+        let mut state = State::try_from(InitArg {
+            greeting: "Hello".to_string(),
+            ..Default::default()
+        })
+        .unwrap();
+        for canister_version in 0..MAX_UPGRADE_HISTORY_LEN as u64 + 2 {
+            apply_event(
+                &mut state,
+                Event::Upgraded {
+                    canister_version,
+                    timestamp: canister_version,
+                    arg: Arg::InitArg(InitArg::default()),
+                },
+            );
+        }
+        assert_eq!(state.upgrade_history.len(), MAX_UPGRADE_HISTORY_LEN);
+        assert_eq!(state.upgrade_history[0].canister_version, 2);
+    }
 }
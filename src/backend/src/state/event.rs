@@ -0,0 +1,31 @@
+use candid::CandidType;
+use serde::Deserialize;
+
+use crate::lifecycle::Arg;
+
+/// A state changing event that is persisted in the event log.
+#[derive(Clone, Eq, PartialEq, Debug, CandidType, Deserialize)]
+pub enum Event {
+    /// The given name was greeted.
+    Greeted(String),
+    /// The given name was forgotten. All prior occurrences of the name are
+    /// ignored when replaying the event log and removed on compaction.
+    Forgotten(String),
+    /// The log was compacted, removing the given number of `Greeted` events.
+    /// Keeps the total number of greetings when replaying the compacted log.
+    Compacted { removed_greetings: u64 },
+    /// The stable memory layout was migrated from `from_version` to
+    /// `to_version`, as described by `description`. Events recorded before the
+    /// description was introduced have none.
+    Migrated {
+        from_version: u32,
+        to_version: u32,
+        description: Option<String>,
+    },
+    /// The canister was installed or upgraded with the given argument.
+    Upgraded {
+        canister_version: u64,
+        timestamp: u64,
+        arg: Arg,
+    },
+}
@@ -1,37 +1,135 @@
+use crate::lifecycle::InitArg;
+use crate::state::event::Event;
+use candid::{Decode, Encode};
 use ic_stable_structures::{
+    cell::Cell as StableCell,
     log::Log as StableLog,
     memory_manager::{MemoryId, MemoryManager, VirtualMemory},
-    DefaultMemoryImpl,
+    storable::Bound,
+    DefaultMemoryImpl, Memory, Storable,
 };
+use std::borrow::Cow;
 use std::cell::RefCell;
 
+pub mod log_buffer;
+pub mod metrics_history;
+pub mod schema;
+
 const LOG_INDEX_MEMORY_ID: MemoryId = MemoryId::new(0);
 const LOG_DATA_MEMORY_ID: MemoryId = MemoryId::new(1);
+const CONFIG_MEMORY_ID: MemoryId = MemoryId::new(2);
+const SCHEMA_VERSION_MEMORY_ID: MemoryId = MemoryId::new(3);
+const MIGRATION_CURSOR_MEMORY_ID: MemoryId = MemoryId::new(4);
+const INFO_LOG_MEMORY_ID: MemoryId = MemoryId::new(5);
+const DEBUG_LOG_MEMORY_ID: MemoryId = MemoryId::new(6);
+const ERROR_LOG_MEMORY_ID: MemoryId = MemoryId::new(7);
+const WARN_LOG_MEMORY_ID: MemoryId = MemoryId::new(8);
+const TRACE_LOG_MEMORY_ID: MemoryId = MemoryId::new(9);
+const MIN_LOG_PRIORITY_MEMORY_ID: MemoryId = MemoryId::new(10);
+const LOG_EVICTIONS_MEMORY_ID: MemoryId = MemoryId::new(11);
+const METRICS_HISTORY_MEMORY_ID: MemoryId = MemoryId::new(12);
+const SECONDARY_LOG_INDEX_MEMORY_ID: MemoryId = MemoryId::new(13);
+const SECONDARY_LOG_DATA_MEMORY_ID: MemoryId = MemoryId::new(14);
+const USES_SECONDARY_LOG_MEMORY_ID: MemoryId = MemoryId::new(15);
+const UNUSED_EVENT_LOG_ZEROING_MEMORY_ID: MemoryId = MemoryId::new(16);
+
+/// Marks the event log that is not in use as completely zeroed.
+const UNUSED_EVENT_LOG_ZEROED: u64 = u64::MAX;
 
 type VMem = VirtualMemory<DefaultMemoryImpl>;
-type EventLog = StableLog<String, VMem, VMem>;
+type EventLog = StableLog<Event, VMem, VMem>;
+
+impl Storable for Event {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).expect("failed to encode event"))
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        // Events written as plain text by version 0 of the stable memory layout
+        // are re-encoded by the migration to version 2.
+        Decode!(&bytes, Event).expect("failed to decode event")
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+impl Storable for InitArg {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).expect("failed to encode config"))
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(&bytes, InitArg).expect("failed to decode config")
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
 
 thread_local! {
     static MEMORY_MANAGER: RefCell<MemoryManager<DefaultMemoryImpl>> = RefCell::new(
         MemoryManager::init(DefaultMemoryImpl::default())
     );
 
-    /// The log of the names that have been greeted.
-    static EVENTS: RefCell<EventLog> = MEMORY_MANAGER
+    /// Whether the event log is stored in the secondary memories. Rewriting
+    /// the log writes the retained events into the memories that are not in
+    /// use and then switches to them.
+    static USES_SECONDARY_LOG: RefCell<StableCell<bool, VMem>> = MEMORY_MANAGER
+        .with(|m|
+              RefCell::new(
+                  StableCell::init(m.borrow().get(USES_SECONDARY_LOG_MEMORY_ID), false)
+                      .expect("failed to initialize stable cell")
+              )
+        );
+
+    /// The offset in the data memory of the event log that is not in use from
+    /// which it still has to be zeroed, or [`UNUSED_EVENT_LOG_ZEROED`]. It is
+    /// persisted so that zeroing is resumed after an upgrade.
+    static UNUSED_EVENT_LOG_ZEROING: RefCell<StableCell<u64, VMem>> = MEMORY_MANAGER
         .with(|m|
               RefCell::new(
-                  StableLog::init(
-                      m.borrow().get(LOG_INDEX_MEMORY_ID),
-                      m.borrow().get(LOG_DATA_MEMORY_ID)
-                  ).expect("failed to initialize stable log")
+                  StableCell::init(
+                      m.borrow().get(UNUSED_EVENT_LOG_ZEROING_MEMORY_ID),
+                      UNUSED_EVENT_LOG_ZEROED
+                  ).expect("failed to initialize stable cell")
               )
         );
+
+    /// The log of the state changing events.
+    static EVENTS: RefCell<EventLog> = {
+        let (index_memory, data_memory) = event_log_memories(uses_secondary_log());
+        RefCell::new(
+            StableLog::init(index_memory, data_memory).expect("failed to initialize stable log")
+        )
+    };
+
+    /// The config of the canister, as set by the last installation or upgrade.
+    static CONFIG: RefCell<StableCell<InitArg, VMem>> = MEMORY_MANAGER
+        .with(|m|
+              RefCell::new(
+                  StableCell::init(
+                      m.borrow().get(CONFIG_MEMORY_ID),
+                      InitArg::default()
+                  ).expect("failed to initialize stable cell")
+              )
+        );
+}
+
+/// Returns the persisted config of the canister.
+pub fn get_config() -> InitArg {
+    CONFIG.with(|config| config.borrow().get().clone())
+}
+
+/// Persists the config of the canister.
+pub fn set_config(config: InitArg) {
+    CONFIG
+        .with(|cell| cell.borrow_mut().set(config))
+        .expect("persisting the config should succeed");
 }
 
 /// Appends the event to the event log.
-pub fn record_event(payload: String) {
+pub fn record_event(event: &Event) {
     EVENTS
-        .with(|events| events.borrow().append(&payload))
+        .with(|events| events.borrow().append(event))
         .expect("recording an event should succeed");
 }
 
@@ -42,7 +140,167 @@ pub fn total_event_count() -> u64 {
 
 pub fn with_event_iter<F, R>(f: F) -> R
 where
-    F: for<'a> FnOnce(Box<dyn Iterator<Item = String> + 'a>) -> R,
+    F: for<'a> FnOnce(Box<dyn Iterator<Item = Event> + 'a>) -> R,
 {
     EVENTS.with(|events| f(Box::new(events.borrow().iter())))
 }
+
+fn uses_secondary_log() -> bool {
+    USES_SECONDARY_LOG.with(|cell| *cell.borrow().get())
+}
+
+/// Returns the index and data memories of the primary or secondary event log.
+fn event_log_memories(secondary: bool) -> (VMem, VMem) {
+    MEMORY_MANAGER.with(|m| {
+        let m = m.borrow();
+        if secondary {
+            (
+                m.get(SECONDARY_LOG_INDEX_MEMORY_ID),
+                m.get(SECONDARY_LOG_DATA_MEMORY_ID),
+            )
+        } else {
+            (m.get(LOG_INDEX_MEMORY_ID), m.get(LOG_DATA_MEMORY_ID))
+        }
+    })
+}
+
+/// Returns the event at the given index of the event log.
+pub fn read_event(index: u64) -> Option<Event> {
+    EVENTS.with(|events| events.borrow().get(index))
+}
+
+/// Returns the encoded event at the given index of the event log, without
+/// decoding it.
+pub fn read_raw_event(index: u64) -> Option<Vec<u8>> {
+    let mut bytes = vec![];
+    EVENTS
+        .with(|events| events.borrow().read_entry(index, &mut bytes))
+        .ok()?;
+    Some(bytes)
+}
+
+/// Starts rewriting the event log into the memories that are not in use,
+/// discarding any unfinished previous rewrite. The events are appended with
+/// [`record_rewritten_event`] and the rewritten log replaces the event log in
+/// [`finish_rewriting_events`].
+///
+/// Rewriting in chunks bounds the instructions per message. Events recorded
+/// in the meantime are appended to the current log and have to be copied as
+/// well before the rewrite is finished.
+///
+/// The memories that are not in use must have been zeroed with
+/// [`zero_unused_event_log`] before, so that no bytes of a previous log
+/// remain past the end of the rewritten log.
+pub fn start_rewriting_events() {
+    assert!(
+        is_unused_event_log_zeroed(),
+        "BUG: the unused event log has not been zeroed"
+    );
+    let (index_memory, data_memory) = event_log_memories(!uses_secondary_log());
+    EventLog::new(index_memory, data_memory);
+}
+
+fn rewritten_event_log() -> EventLog {
+    let (index_memory, data_memory) = event_log_memories(!uses_secondary_log());
+    StableLog::init(index_memory, data_memory).expect("failed to initialize stable log")
+}
+
+/// Appends the event to the log started by [`start_rewriting_events`].
+pub fn record_rewritten_event(event: &Event) {
+    rewritten_event_log()
+        .append(event)
+        .expect("recording an event should succeed");
+}
+
+/// Replaces the event log with the rewritten log. The memories of the
+/// previous log are no longer in use and have to be zeroed with
+/// [`zero_unused_event_log`].
+pub fn finish_rewriting_events() {
+    let rewritten = rewritten_event_log();
+    let secondary = !uses_secondary_log();
+    USES_SECONDARY_LOG
+        .with(|cell| cell.borrow_mut().set(secondary))
+        .expect("persisting the event log memories should succeed");
+    set_unused_event_log_zeroing(0);
+    EVENTS.with(|events| *events.borrow_mut() = rewritten);
+}
+
+fn set_unused_event_log_zeroing(offset: u64) {
+    UNUSED_EVENT_LOG_ZEROING
+        .with(|cell| cell.borrow_mut().set(offset))
+        .expect("persisting the zeroing offset should succeed");
+}
+
+/// Whether the memories of the event log that is not in use hold no events.
+pub fn is_unused_event_log_zeroed() -> bool {
+    UNUSED_EVENT_LOG_ZEROING.with(|cell| *cell.borrow().get()) == UNUSED_EVENT_LOG_ZEROED
+}
+
+/// Zeroes up to `max_bytes` of the data memory of the event log that is not
+/// in use, continuing at the persisted offset, so that the payloads of dropped
+/// events do not linger in stable memory. Returns `true` once the whole memory
+/// is zeroed.
+pub fn zero_unused_event_log(max_bytes: u64) -> bool {
+    const WASM_PAGE_SIZE_IN_BYTES: u64 = 65536;
+    const CHUNK_SIZE: u64 = 64 * 1024;
+
+    let offset = UNUSED_EVENT_LOG_ZEROING.with(|cell| *cell.borrow().get());
+    if offset == UNUSED_EVENT_LOG_ZEROED {
+        return true;
+    }
+    let (index_memory, data_memory) = event_log_memories(!uses_secondary_log());
+    let size = data_memory.size() * WASM_PAGE_SIZE_IN_BYTES;
+    let end = offset.saturating_add(max_bytes).min(size);
+    let mut chunk_offset = offset;
+    while chunk_offset < end {
+        let len = CHUNK_SIZE.min(end - chunk_offset);
+        data_memory.write(chunk_offset, &vec![0; len as usize]);
+        chunk_offset += len;
+    }
+    if end < size {
+        set_unused_event_log_zeroing(end);
+        return false;
+    }
+    EventLog::new(index_memory, data_memory);
+    set_unused_event_log_zeroing(UNUSED_EVENT_LOG_ZEROED);
+    true
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::state::event::Event;
+    use crate::storage::{
+        event_log_memories, finish_rewriting_events, is_unused_event_log_zeroed, record_event,
+        record_rewritten_event, start_rewriting_events, with_event_iter, zero_unused_event_log,
+    };
+    use ic_stable_structures::Memory;
+
+    #[test]
+    fn should_zero_the_replaced_event_log_across_messages() {
+        record_event(&Event::Greeted("alice".to_string()));
+        record_event(&Event::Greeted("bob".to_string()));
+        assert!(is_unused_event_log_zeroed());
+
+        start_rewriting_events();
+        record_rewritten_event(&Event::Greeted("bob".to_string()));
+        finish_rewriting_events();
+        assert_eq!(
+            with_event_iter(|events| events.collect::<Vec<_>>()),
+            vec![Event::Greeted("bob".to_string())]
+        );
+
+        // the progress is persisted, e.g. across upgrades
+        let mut messages = 0;
+        while !zero_unused_event_log(16 * 1024) {
+            assert!(!is_unused_event_log_zeroed());
+            messages += 1;
+        }
+        assert!(messages > 1);
+        assert!(is_unused_event_log_zeroed());
+
+        let (_, data_memory) = event_log_memories(false);
+        let mut bytes = vec![0; (data_memory.size() * 65536) as usize];
+        data_memory.read(0, &mut bytes);
+        assert!(!bytes.windows(5).any(|window| window == b"alice"));
+    }
+}
@@ -0,0 +1,313 @@
+//! Bounded buffers of log entries in stable memory, one per priority, so that
+//! logs survive canister upgrades.
+use super::{
+    VMem, DEBUG_LOG_MEMORY_ID, ERROR_LOG_MEMORY_ID, INFO_LOG_MEMORY_ID, LOG_EVICTIONS_MEMORY_ID,
+    MEMORY_MANAGER, MIN_LOG_PRIORITY_MEMORY_ID, TRACE_LOG_MEMORY_ID, WARN_LOG_MEMORY_ID,
+};
+use crate::logs::{LogEntry, LogFields, Priority};
+use candid::{CandidType, Decode, Encode};
+use ic_stable_structures::{
+    cell::Cell as StableCell, storable::Bound, BTreeMap as StableBTreeMap, Storable,
+};
+use serde::Deserialize;
+use std::borrow::Cow;
+use std::cell::{Cell, RefCell};
+use std::ops::RangeBounds;
+use std::thread::LocalKey;
+
+#[derive(Clone, Debug, CandidType, Deserialize)]
+struct StoredLogEntry {
+    timestamp: u64,
+    file: String,
+    line: u32,
+    message: String,
+    /// Not set for entries written before structured fields were introduced.
+    fields: Option<LogFields>,
+}
+
+impl Storable for StoredLogEntry {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).expect("failed to encode log entry"))
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(&bytes, StoredLogEntry).expect("failed to decode log entry")
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+/// Accounting of the entries of a priority that were evicted from its buffer.
+#[derive(Copy, Clone, Debug, Default, Eq, PartialEq, CandidType, Deserialize)]
+pub struct EvictionStats {
+    /// The number of evicted entries.
+    pub evicted: u64,
+    /// The timestamp of the most recently evicted entry.
+    pub last_evicted_timestamp: u64,
+}
+
+impl Storable for EvictionStats {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).expect("failed to encode eviction stats"))
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(&bytes, EvictionStats).expect("failed to decode eviction stats")
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+impl Storable for Priority {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(vec![self.verbosity()])
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Priority::ALL
+            .into_iter()
+            .find(|priority| priority.verbosity() == bytes[0])
+            .expect("failed to decode priority")
+    }
+
+    const BOUND: Bound = Bound::Bounded {
+        max_size: 1,
+        is_fixed_size: true,
+    };
+}
+
+/// The minimum priority of entries that are logged by default.
+const DEFAULT_MIN_LOG_PRIORITY: Priority = Priority::Debug;
+
+/// Log entries keyed by their counter, which is unique across all priorities.
+type LogBuffer = StableBTreeMap<u64, StoredLogEntry, VMem>;
+
+thread_local! {
+    static ERROR_LOG: RefCell<LogBuffer> = MEMORY_MANAGER
+        .with(|m| RefCell::new(StableBTreeMap::init(m.borrow().get(ERROR_LOG_MEMORY_ID))));
+
+    static WARN_LOG: RefCell<LogBuffer> = MEMORY_MANAGER
+        .with(|m| RefCell::new(StableBTreeMap::init(m.borrow().get(WARN_LOG_MEMORY_ID))));
+
+    static INFO_LOG: RefCell<LogBuffer> = MEMORY_MANAGER
+        .with(|m| RefCell::new(StableBTreeMap::init(m.borrow().get(INFO_LOG_MEMORY_ID))));
+
+    static DEBUG_LOG: RefCell<LogBuffer> = MEMORY_MANAGER
+        .with(|m| RefCell::new(StableBTreeMap::init(m.borrow().get(DEBUG_LOG_MEMORY_ID))));
+
+    static TRACE_LOG: RefCell<LogBuffer> = MEMORY_MANAGER
+        .with(|m| RefCell::new(StableBTreeMap::init(m.borrow().get(TRACE_LOG_MEMORY_ID))));
+
+    /// Entries below this priority are dropped.
+    static MIN_LOG_PRIORITY: RefCell<StableCell<Priority, VMem>> = MEMORY_MANAGER
+        .with(|m|
+              RefCell::new(
+                  StableCell::init(
+                      m.borrow().get(MIN_LOG_PRIORITY_MEMORY_ID),
+                      DEFAULT_MIN_LOG_PRIORITY
+                  ).expect("failed to initialize stable cell")
+              )
+        );
+
+    static EVICTIONS: RefCell<StableBTreeMap<Priority, EvictionStats, VMem>> = MEMORY_MANAGER
+        .with(|m| RefCell::new(StableBTreeMap::init(m.borrow().get(LOG_EVICTIONS_MEMORY_ID))));
+
+    /// The number of retained entries per priority. No entries are evicted
+    /// until the capacity is configured, so that logging before the config is
+    /// loaded cannot shrink the buffers.
+    static CAPACITY: Cell<Option<u64>> = const { Cell::new(None) };
+
+    /// The counter of the next log entry, lazily restored from the buffers.
+    static NEXT_COUNTER: Cell<Option<u64>> = const { Cell::new(None) };
+}
+
+fn buffer(priority: Priority) -> &'static LocalKey<RefCell<LogBuffer>> {
+    match priority {
+        Priority::Error => &ERROR_LOG,
+        Priority::Warn => &WARN_LOG,
+        Priority::Info => &INFO_LOG,
+        Priority::Debug => &DEBUG_LOG,
+        Priority::Trace => &TRACE_LOG,
+    }
+}
+
+fn next_counter() -> u64 {
+    NEXT_COUNTER.with(|next| {
+        let counter = next.get().unwrap_or_else(|| {
+            Priority::ALL
+                .iter()
+                .filter_map(|priority| {
+                    buffer(*priority)
+                        .with_borrow(|log| log.last_key_value().map(|(counter, _)| counter + 1))
+                })
+                .max()
+                .unwrap_or_default()
+        });
+        next.set(Some(counter + 1));
+        counter
+    })
+}
+
+fn evict(priority: Priority, log: &mut LogBuffer, capacity: u64) {
+    let mut stats = eviction_stats(priority);
+    while log.len() > capacity {
+        if let Some((_, entry)) = log.pop_first() {
+            stats.evicted += 1;
+            stats.last_evicted_timestamp = entry.timestamp;
+        }
+    }
+    if stats != eviction_stats(priority) {
+        EVICTIONS.with_borrow_mut(|evictions| evictions.insert(priority, stats));
+    }
+}
+
+/// Appends the entry to the buffer of the given priority, evicting the oldest
+/// entries of that priority if the buffer is full.
+pub fn append_log_entry(priority: Priority, entry: ic_canister_log::LogEntry, fields: LogFields) {
+    let counter = next_counter();
+    buffer(priority).with_borrow_mut(|log| {
+        log.insert(
+            counter,
+            StoredLogEntry {
+                timestamp: entry.timestamp,
+                file: entry.file.to_string(),
+                line: entry.line,
+                message: entry.message,
+                fields: Some(fields),
+            },
+        );
+        if let Some(capacity) = CAPACITY.get() {
+            evict(priority, log, capacity);
+        }
+    });
+}
+
+/// Sets the number of retained entries per priority, evicting the oldest
+/// entries of buffers that exceed the new capacity.
+pub fn set_log_capacity(capacity: u64) {
+    CAPACITY.set(Some(capacity));
+    for priority in Priority::ALL {
+        buffer(priority).with_borrow_mut(|log| evict(priority, log, capacity));
+    }
+}
+
+/// Returns the number of retained entries per priority, if configured.
+pub fn log_capacity() -> Option<u64> {
+    CAPACITY.get()
+}
+
+/// Returns the number of retained entries of the given priority.
+pub fn log_buffer_len(priority: Priority) -> u64 {
+    buffer(priority).with_borrow(|log| log.len())
+}
+
+/// Returns the accounting of the evicted entries of the given priority.
+pub fn eviction_stats(priority: Priority) -> EvictionStats {
+    EVICTIONS.with_borrow(|evictions| evictions.get(&priority).unwrap_or_default())
+}
+
+/// Returns the minimum priority of entries that are logged.
+pub fn min_log_priority() -> Priority {
+    MIN_LOG_PRIORITY.with(|priority| *priority.borrow().get())
+}
+
+/// Sets the minimum priority of entries that are logged.
+pub fn set_min_log_priority(priority: Priority) {
+    MIN_LOG_PRIORITY
+        .with(|cell| cell.borrow_mut().set(priority))
+        .expect("persisting the minimum log priority should succeed");
+}
+
+/// Returns the entries of the given priority in the order of insertion.
+pub fn export_log_entries(priority: Priority) -> Vec<LogEntry> {
+    export_log_entries_in(priority, ..)
+}
+
+/// Returns the entries of the given priority with a counter in the given range
+/// in the order of insertion, reading only those entries from stable memory.
+pub fn export_log_entries_in(priority: Priority, counters: impl RangeBounds<u64>) -> Vec<LogEntry> {
+    buffer(priority).with_borrow(|log| {
+        log.range(counters)
+            .map(|(counter, entry)| LogEntry {
+                timestamp: entry.timestamp,
+                priority,
+                file: entry.file,
+                line: entry.line,
+                message: entry.message,
+                counter,
+                fields: entry.fields.unwrap_or_default(),
+                truncated_bytes: None,
+            })
+            .collect()
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::logs::Priority;
+    use crate::storage::log_buffer::{
+        append_log_entry, eviction_stats, export_log_entries, log_buffer_len, set_log_capacity,
+        EvictionStats,
+    };
+
+    fn append(priority: Priority, message: &str) {
+        append_log_entry(
+            priority,
+            ic_canister_log::LogEntry {
+                timestamp: message.len() as u64,
+                counter: 0,
+                message: message.to_string(),
+                file: file!(),
+                line: line!(),
+            },
+            Default::default(),
+        );
+    }
+
+    fn messages(priority: Priority) -> Vec<(u64, String)> {
+        export_log_entries(priority)
+            .into_iter()
+            .map(|entry| (entry.counter, entry.message))
+            .collect()
+    }
+
+    #[test]
+    fn should_evict_oldest_entries_per_priority() {
+        for i in 0..5 {
+            append(Priority::Info, &format!("info {}", i));
+        }
+        append(Priority::Debug, "debug");
+        assert_eq!(export_log_entries(Priority::Info).len(), 5);
+
+        set_log_capacity(3);
+        assert_eq!(
+            messages(Priority::Info),
+            vec![
+                (2, "info 2".to_string()),
+                (3, "info 3".to_string()),
+                (4, "info 4".to_string())
+            ]
+        );
+
+        append(Priority::Info, "info 6");
+        assert_eq!(
+            messages(Priority::Info),
+            vec![
+                (3, "info 3".to_string()),
+                (4, "info 4".to_string()),
+                (6, "info 6".to_string())
+            ]
+        );
+        assert_eq!(messages(Priority::Debug), vec![(5, "debug".to_string())]);
+
+        assert_eq!(log_buffer_len(Priority::Info), 3);
+        assert_eq!(
+            eviction_stats(Priority::Info),
+            EvictionStats {
+                evicted: 3,
+                last_evicted_timestamp: "info 2".len() as u64,
+            }
+        );
+        assert_eq!(eviction_stats(Priority::Debug), EvictionStats::default());
+    }
+}
@@ -0,0 +1,111 @@
+//! A bounded history of metrics samples in stable memory, so that trends such
+//! as the cycle burn survive upgrades and can be read without Prometheus.
+use super::{VMem, MEMORY_MANAGER, METRICS_HISTORY_MEMORY_ID};
+use candid::{CandidType, Decode, Encode};
+use ic_stable_structures::{storable::Bound, BTreeMap as StableBTreeMap, Storable};
+use serde::{Deserialize, Serialize};
+use std::borrow::Cow;
+use std::cell::RefCell;
+use std::collections::BTreeMap;
+
+/// The number of retained samples, the oldest samples are evicted first.
+pub const METRICS_HISTORY_CAPACITY: u64 = 2_016;
+/// The maximum number of samples returned by `/metrics/history` at once.
+pub const MAX_METRICS_HISTORY_LIMIT: usize = 288;
+
+#[derive(Clone, Debug, Default, PartialEq, CandidType, Serialize, Deserialize)]
+pub struct MetricsSample {
+    /// The time of the sample in nanoseconds since the epoch.
+    pub timestamp: u64,
+    pub cycle_balance: u128,
+    pub stable_memory_bytes: u64,
+    pub heap_memory_bytes: u64,
+    /// The number of successful calls per canister method since the last upgrade.
+    pub method_calls: BTreeMap<String, u64>,
+}
+
+impl Storable for MetricsSample {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).expect("failed to encode metrics sample"))
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(&bytes, MetricsSample).expect("failed to decode metrics sample")
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+thread_local! {
+    /// Samples keyed by their timestamp.
+    static HISTORY: RefCell<StableBTreeMap<u64, MetricsSample, VMem>> = MEMORY_MANAGER
+        .with(|m| RefCell::new(StableBTreeMap::init(m.borrow().get(METRICS_HISTORY_MEMORY_ID))));
+}
+
+/// Appends the sample to the history, evicting the oldest samples if the
+/// history is full.
+pub fn record_metrics_sample(sample: MetricsSample) {
+    HISTORY.with_borrow_mut(|history| {
+        history.insert(sample.timestamp, sample);
+        while history.len() > METRICS_HISTORY_CAPACITY {
+            history.pop_first();
+        }
+    })
+}
+
+/// Returns up to `limit` samples taken at or after `since`, oldest first.
+pub fn metrics_history_since(since: u64, limit: usize) -> Vec<MetricsSample> {
+    HISTORY.with_borrow(|history| {
+        history
+            .range(since..)
+            .take(limit)
+            .map(|(_, sample)| sample)
+            .collect()
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::storage::metrics_history::{
+        metrics_history_since, record_metrics_sample, MetricsSample, METRICS_HISTORY_CAPACITY,
+    };
+
+    fn timestamps(since: u64) -> Vec<u64> {
+        metrics_history_since(since, usize::MAX)
+            .into_iter()
+            .map(|sample| sample.timestamp)
+            .collect()
+    }
+
+    #[test]
+    fn should_evict_oldest_samples() {
+        for timestamp in 0..METRICS_HISTORY_CAPACITY + 2 {
+            record_metrics_sample(MetricsSample {
+                timestamp,
+                cycle_balance: 1_000_000 - timestamp as u128,
+                ..Default::default()
+            });
+        }
+
+        assert_eq!(
+            timestamps(0),
+            (2..METRICS_HISTORY_CAPACITY + 2).collect::<Vec<_>>()
+        );
+        assert_eq!(
+            timestamps(METRICS_HISTORY_CAPACITY),
+            vec![METRICS_HISTORY_CAPACITY, METRICS_HISTORY_CAPACITY + 1]
+        );
+        assert_eq!(
+            metrics_history_since(METRICS_HISTORY_CAPACITY + 1, usize::MAX)[0].cycle_balance,
+            1_000_000 - (METRICS_HISTORY_CAPACITY as u128 + 1)
+        );
+        assert_eq!(timestamps(METRICS_HISTORY_CAPACITY + 2), Vec::<u64>::new());
+        assert_eq!(
+            metrics_history_since(0, 2)
+                .into_iter()
+                .map(|sample| sample.timestamp)
+                .collect::<Vec<_>>(),
+            vec![2, 3]
+        );
+    }
+}
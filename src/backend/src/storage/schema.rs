@@ -0,0 +1,389 @@
+//! Versioning and migration of the stable memory layout.
+//!
+//! The version of the layout that was last written is stored in its own
+//! memory, so that an upgrade can detect whether the stable memory was written
+//! by an older or a newer version of the canister.
+use super::{
+    finish_rewriting_events, read_raw_event, record_event, record_rewritten_event,
+    start_rewriting_events, total_event_count, zero_unused_event_log, VMem, MEMORY_MANAGER,
+    MIGRATION_CURSOR_MEMORY_ID, SCHEMA_VERSION_MEMORY_ID,
+};
+use crate::state::event::Event;
+use candid::Decode;
+use ic_stable_structures::cell::Cell as StableCell;
+use std::cell::RefCell;
+
+/// A migration of the stable memory layout from version `from_version` to
+/// version `from_version + 1`.
+pub struct Migration {
+    pub from_version: u32,
+    pub description: &'static str,
+    /// Migrates the chunk starting at the given cursor and returns the cursor
+    /// of the next chunk, or `None` if the migration is complete.
+    ///
+    /// A chunk and the cursor following it are persisted in the same message,
+    /// so a migration can be resumed in a later message after any chunk.
+    pub step: fn(u64) -> Option<u64>,
+}
+
+/// The registry of all migrations, ordered by the version they migrate from.
+const MIGRATIONS: &[Migration] = &[
+    Migration {
+        from_version: 0,
+        description: "introduce the stable memory schema version",
+        step: migrate_v0_to_v1,
+    },
+    Migration {
+        from_version: 1,
+        description: "re-encode the plain text events of version 0 as Candid",
+        step: migrate_v1_to_v2,
+    },
+];
+
+/// The version of the stable memory layout written by this code.
+pub const SCHEMA_VERSION: u32 = MIGRATIONS.len() as u32;
+
+/// The number of instructions after which no further migration chunks are
+/// started in the current message. This leaves enough headroom below the
+/// instruction limit of both `post_upgrade` and timers.
+const MIGRATION_INSTRUCTIONS_PER_MESSAGE: u64 = 20_000_000_000;
+
+/// The number of events that are re-encoded per migration chunk.
+const EVENTS_PER_MIGRATION_CHUNK: u64 = 1_000;
+
+/// The number of bytes of a replaced event log that are zeroed per migration chunk.
+const BYTES_ZEROED_PER_MIGRATION_CHUNK: u64 = 1024 * 1024;
+
+/// The cursor of the chunks that zero the event log replaced by a migration.
+const ZEROING_CURSOR: u64 = u64::MAX;
+
+thread_local! {
+    /// The version of the stable memory layout. Memory written before the
+    /// introduction of the version marker defaults to version 0.
+    static STORED_SCHEMA_VERSION: RefCell<StableCell<u32, VMem>> = MEMORY_MANAGER
+        .with(|m|
+              RefCell::new(
+                  StableCell::init(m.borrow().get(SCHEMA_VERSION_MEMORY_ID), 0)
+                      .expect("failed to initialize stable cell")
+              )
+        );
+
+    /// The cursor of the next chunk of the migration from the stored version.
+    static MIGRATION_CURSOR: RefCell<StableCell<u64, VMem>> = MEMORY_MANAGER
+        .with(|m|
+              RefCell::new(
+                  StableCell::init(m.borrow().get(MIGRATION_CURSOR_MEMORY_ID), 0)
+                      .expect("failed to initialize stable cell")
+              )
+        );
+}
+
+#[derive(Eq, PartialEq, Debug)]
+pub enum SchemaError {
+    /// The stable memory was written by a newer version of the canister.
+    UnsupportedVersion { stored: u32, supported: u32 },
+}
+
+#[derive(Eq, PartialEq, Debug)]
+pub enum MigrationStatus {
+    /// The stable memory layout is at [`SCHEMA_VERSION`].
+    Completed,
+    /// The instruction budget of the current message was exhausted, the
+    /// migrations have to be resumed in a later message.
+    Pending,
+}
+
+/// Returns the version of the stable memory layout that was last written.
+pub fn stored_schema_version() -> u32 {
+    STORED_SCHEMA_VERSION.with(|version| *version.borrow().get())
+}
+
+/// Marks the stable memory as written with the given layout version.
+pub fn set_schema_version(version: u32) {
+    STORED_SCHEMA_VERSION
+        .with(|cell| cell.borrow_mut().set(version))
+        .expect("persisting the schema version should succeed");
+}
+
+fn migration_cursor() -> u64 {
+    MIGRATION_CURSOR.with(|cursor| *cursor.borrow().get())
+}
+
+fn set_migration_cursor(cursor: u64) {
+    MIGRATION_CURSOR
+        .with(|cell| cell.borrow_mut().set(cursor))
+        .expect("persisting the migration cursor should succeed");
+}
+
+/// Fails if the stable memory was written by a newer version of the canister.
+pub fn check_schema_version() -> Result<(), SchemaError> {
+    let stored = stored_schema_version();
+    if stored > SCHEMA_VERSION {
+        return Err(SchemaError::UnsupportedVersion {
+            stored,
+            supported: SCHEMA_VERSION,
+        });
+    }
+    Ok(())
+}
+
+/// Runs the pending migrations up to [`SCHEMA_VERSION`] until the instruction
+/// budget of the current message is exhausted.
+///
+/// Fails without writing anything if the stable memory was written by a newer
+/// version of the canister.
+pub fn migrate() -> Result<MigrationStatus, SchemaError> {
+    run_migrations(MIGRATIONS, has_instruction_budget)
+}
+
+/// Whether further chunks of long running work, such as migrations or the
+/// compaction of the event log, can be started in the current message.
+pub fn has_instruction_budget() -> bool {
+    ic_cdk::api::instruction_counter() < MIGRATION_INSTRUCTIONS_PER_MESSAGE
+}
+
+fn run_migrations(
+    migrations: &[Migration],
+    mut has_budget: impl FnMut() -> bool,
+) -> Result<MigrationStatus, SchemaError> {
+    let stored = stored_schema_version();
+    let supported = migrations.len() as u32;
+    if stored > supported {
+        return Err(SchemaError::UnsupportedVersion { stored, supported });
+    }
+    for migration in &migrations[stored as usize..] {
+        assert_eq!(
+            migration.from_version,
+            stored_schema_version(),
+            "BUG: migrations are not ordered by version"
+        );
+        let mut cursor = Some(migration_cursor());
+        while let Some(chunk) = cursor {
+            if !has_budget() {
+                return Ok(MigrationStatus::Pending);
+            }
+            cursor = (migration.step)(chunk);
+            set_migration_cursor(cursor.unwrap_or_default());
+        }
+        let to_version = migration.from_version + 1;
+        set_schema_version(to_version);
+        record_event(&Event::Migrated {
+            from_version: migration.from_version,
+            to_version,
+            description: Some(migration.description.to_string()),
+        });
+    }
+    Ok(MigrationStatus::Completed)
+}
+
+/// Version 1 only introduced the schema version, the plain text events of
+/// version 0 are re-encoded by [`migrate_v1_to_v2`].
+fn migrate_v0_to_v1(_cursor: u64) -> Option<u64> {
+    None
+}
+
+/// Versions 0 and 1 may contain greeted names stored as plain text in the
+/// event log. Rewrites the log with all events encoded as Candid, so that
+/// events are decoded strictly afterwards, and then zeroes the previous log.
+fn migrate_v1_to_v2(cursor: u64) -> Option<u64> {
+    if cursor == ZEROING_CURSOR {
+        return (!zero_unused_event_log(BYTES_ZEROED_PER_MIGRATION_CHUNK))
+            .then_some(ZEROING_CURSOR);
+    }
+    if cursor == 0 {
+        if !zero_unused_event_log(BYTES_ZEROED_PER_MIGRATION_CHUNK) {
+            return Some(0);
+        }
+        start_rewriting_events();
+    }
+    let end = (cursor + EVENTS_PER_MIGRATION_CHUNK).min(total_event_count());
+    for index in cursor..end {
+        let bytes = read_raw_event(index).expect("BUG: event index out of bounds");
+        record_rewritten_event(&decode_v0_event(bytes));
+    }
+    if end < total_event_count() {
+        return Some(end);
+    }
+    finish_rewriting_events();
+    Some(ZEROING_CURSOR)
+}
+
+/// Decodes an event written by version 0 or 1 of the layout, where greeted
+/// names may be stored as plain UTF-8 text.
+fn decode_v0_event(bytes: Vec<u8>) -> Event {
+    Decode!(&bytes, Event).unwrap_or_else(|_| {
+        Event::Greeted(String::from_utf8(bytes).expect("failed to decode event"))
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::state::event::Event;
+    use crate::storage::schema::{
+        check_schema_version, run_migrations, set_schema_version, stored_schema_version, Migration,
+        MigrationStatus, SchemaError, MIGRATIONS, SCHEMA_VERSION,
+    };
+    use crate::storage::{record_event, total_event_count, with_event_iter};
+
+    const CHUNK_SIZE: u64 = 3;
+    const GREETED_EVENTS: u64 = 10;
+
+    /// Appends [`GREETED_EVENTS`] events in chunks of [`CHUNK_SIZE`].
+    fn greet_in_chunks(cursor: u64) -> Option<u64> {
+        let end = (cursor + CHUNK_SIZE).min(GREETED_EVENTS);
+        for i in cursor..end {
+            record_event(&Event::Greeted(i.to_string()));
+        }
+        (end < GREETED_EVENTS).then_some(end)
+    }
+
+    const TEST_MIGRATIONS: &[Migration] = &[
+        Migration {
+            from_version: 0,
+            description: "greet in chunks",
+            step: greet_in_chunks,
+        },
+        Migration {
+            from_version: 1,
+            description: "no-op",
+            step: |_| None,
+        },
+    ];
+
+    #[test]
+    fn should_have_ordered_migrations() {
+        for (version, migration) in MIGRATIONS.iter().enumerate() {
+            assert_eq!(migration.from_version, version as u32);
+        }
+    }
+
+    #[test]
+    fn should_migrate_older_versions() {
+        set_schema_version(0);
+        assert_eq!(check_schema_version(), Ok(()));
+        assert_eq!(
+            run_migrations(MIGRATIONS, || true),
+            Ok(MigrationStatus::Completed)
+        );
+        assert_eq!(stored_schema_version(), SCHEMA_VERSION);
+    }
+
+    #[test]
+    fn should_resume_migrations_in_chunks() {
+        set_schema_version(0);
+        let mut messages = 0;
+        loop {
+            messages += 1;
+            let mut chunks = 0;
+            let status = run_migrations(TEST_MIGRATIONS, || {
+                chunks += 1;
+                chunks <= 2
+            });
+            if status == Ok(MigrationStatus::Completed) {
+                break;
+            }
+            assert_eq!(status, Ok(MigrationStatus::Pending));
+        }
+
+        assert_eq!(messages, 3);
+        assert_eq!(stored_schema_version(), 2);
+        assert_eq!(total_event_count(), GREETED_EVENTS + 2);
+        let events: Vec<Event> = with_event_iter(|events| events.collect());
+        let greeted: Vec<Event> = (0..GREETED_EVENTS)
+            .map(|i| Event::Greeted(i.to_string()))
+            .collect();
+        assert_eq!(&events[..GREETED_EVENTS as usize], &greeted[..]);
+        assert_eq!(
+            &events[GREETED_EVENTS as usize..],
+            &[
+                Event::Migrated {
+                    from_version: 0,
+                    to_version: 1,
+                    description: Some("greet in chunks".to_string()),
+                },
+                Event::Migrated {
+                    from_version: 1,
+                    to_version: 2,
+                    description: Some("no-op".to_string()),
+                }
+            ]
+        );
+
+        // running the migrations again is a no-op
+        assert_eq!(
+            run_migrations(TEST_MIGRATIONS, || true),
+            Ok(MigrationStatus::Completed)
+        );
+        assert_eq!(total_event_count(), GREETED_EVENTS + 2);
+    }
+
+    #[test]
+    fn should_re_encode_plain_text_events() {
+        use crate::storage::{event_log_memories, is_unused_event_log_zeroed};
+        use candid::Encode;
+        use ic_stable_structures::{log::Log as StableLog, Memory};
+
+        // events of version 0 written as plain text, followed by events of version 1
+        let (index_memory, data_memory) = event_log_memories(false);
+        let raw_log: StableLog<Vec<u8>, _, _> = StableLog::new(index_memory, data_memory);
+        for bytes in [
+            b"alice".to_vec(),
+            Encode!(&Event::Greeted("bob".to_string())).unwrap(),
+            b"carol".to_vec(),
+        ] {
+            raw_log.append(&bytes).unwrap();
+        }
+        set_schema_version(1);
+
+        let mut chunks = 0;
+        while run_migrations(MIGRATIONS, || {
+            chunks += 1;
+            chunks % 2 == 1
+        }) == Ok(MigrationStatus::Pending)
+        {}
+
+        assert_eq!(stored_schema_version(), SCHEMA_VERSION);
+        // the plain text events do not linger in the previous log
+        assert!(is_unused_event_log_zeroed());
+        let (_, data_memory) = event_log_memories(false);
+        let mut bytes = vec![0; (data_memory.size() * 65536) as usize];
+        data_memory.read(0, &mut bytes);
+        for name in [&b"alice"[..], b"bob", b"carol"] {
+            assert!(!bytes.windows(name.len()).any(|window| window == name));
+        }
+        assert_eq!(
+            with_event_iter(|events| events.collect::<Vec<_>>()),
+            vec![
+                Event::Greeted("alice".to_string()),
+                Event::Greeted("bob".to_string()),
+                Event::Greeted("carol".to_string()),
+                Event::Migrated {
+                    from_version: 1,
+                    to_version: 2,
+                    description: Some(MIGRATIONS[1].description.to_string()),
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn should_reject_newer_versions() {
+        set_schema_version(SCHEMA_VERSION + 1);
+        assert_eq!(
+            check_schema_version(),
+            Err(SchemaError::UnsupportedVersion {
+                stored: SCHEMA_VERSION + 1,
+                supported: SCHEMA_VERSION,
+            })
+        );
+        assert_eq!(
+            run_migrations(MIGRATIONS, || true),
+            Err(SchemaError::UnsupportedVersion {
+                stored: SCHEMA_VERSION + 1,
+                supported: SCHEMA_VERSION,
+            })
+        );
+        assert_eq!(stored_schema_version(), SCHEMA_VERSION + 1);
+        assert_eq!(total_event_count(), 0);
+    }
+}
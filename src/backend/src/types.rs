@@ -19,3 +19,38 @@ impl MetricValue for u128 {
         *self as f64
     }
 }
+
+impl MetricValue for usize {
+    fn metric_value(&self) -> f64 {
+        *self as f64
+    }
+}
+
+impl MetricValue for i64 {
+    fn metric_value(&self) -> f64 {
+        *self as f64
+    }
+}
+
+impl MetricValue for f64 {
+    fn metric_value(&self) -> f64 {
+        *self
+    }
+}
+
+impl MetricValue for bool {
+    fn metric_value(&self) -> f64 {
+        if *self {
+            1.0
+        } else {
+            0.0
+        }
+    }
+}
+
+/// Durations are exposed in seconds, as recommended by Prometheus.
+impl MetricValue for std::time::Duration {
+    fn metric_value(&self) -> f64 {
+        self.as_secs_f64()
+    }
+}
@@ -1,9 +1,21 @@
-use backend::lifecycle::{Arg, InitArg};
+use backend::http_types::{HttpRequest, HttpResponse};
+use backend::lifecycle::{
+    Arg, InitArg, UpgradeArg, DEFAULT_LOW_CYCLES_THRESHOLD_SECONDS, DEFAULT_MAX_LOGS_BODY_SIZE,
+    DEFAULT_MAX_LOG_MESSAGE_SIZE, DEFAULT_MAX_NAME_LENGTH,
+};
+use backend::logs::{Log, LogFilter, LogPage, Priority, Sort};
+use backend::metrics::{MetricFamily, SampleValue};
+use backend::state::UpgradeRecord;
+use backend::storage::metrics_history::MetricsSample;
 use candid::{decode_one, encode_one, Principal};
 use pocket_ic::{PocketIc, WasmResult};
 use std::fs;
+use std::time::Duration;
 
 const BACKEND_WASM: &str = "../../target/wasm32-unknown-unknown/release/backend.wasm";
+/// Gzipped Wasm of the canister before the stable memory layout was versioned,
+/// built from the initial commit of this repository.
+const BACKEND_V0_WASM: &str = "tests/fixtures/backend_v0.wasm.gz";
 
 fn setup() -> (PocketIc, Principal) {
     std::env::set_var("POCKET_IC_BIN", "/usr/local/bin/pocket-ic"); // Path of the pocket-ic binary
@@ -14,6 +26,7 @@ fn setup() -> (PocketIc, Principal) {
     let wasm = fs::read(BACKEND_WASM).expect("Wasm file not found, run 'dfx build'.");
     let arg = Arg::InitArg(InitArg {
         greeting: "Hello".to_string(),
+        ..Default::default()
     });
     let encoded_arg = candid::encode_one(arg).expect("Failed to encode InstallArgs");
     pic.install_canister(backend_canister, wasm, encoded_arg, None);
@@ -35,3 +48,408 @@ fn test_hello_world() {
     let result: String = decode_one(&response).unwrap();
     assert_eq!(result, "Hello, ICP!");
 }
+
+#[test]
+fn test_upgrade_keeps_omitted_config() {
+    let (pic, backend_canister) = setup();
+    let wasm = fs::read(BACKEND_WASM).expect("Wasm file not found, run 'dfx build'.");
+
+    let greet = |name: &str| -> String {
+        let Ok(WasmResult::Reply(response)) = pic.update_call(
+            backend_canister,
+            Principal::anonymous(),
+            "greet",
+            encode_one(name).unwrap(),
+        ) else {
+            panic!("Expected reply");
+        };
+        decode_one(&response).unwrap()
+    };
+
+    let arg = Arg::UpgradeArg(UpgradeArg::default());
+    pic.upgrade_canister(
+        backend_canister,
+        wasm.clone(),
+        encode_one(arg).unwrap(),
+        None,
+    )
+    .expect("Failed to upgrade canister");
+    assert_eq!(greet("ICP"), "Hello, ICP!");
+
+    let arg = Arg::UpgradeArg(UpgradeArg {
+        greeting: Some("Hoi".to_string()),
+        ..Default::default()
+    });
+    pic.upgrade_canister(
+        backend_canister,
+        wasm,
+        encode_one(arg.clone()).unwrap(),
+        None,
+    )
+    .expect("Failed to upgrade canister");
+    assert_eq!(greet("ICP"), "Hoi, ICP!");
+
+    let Ok(WasmResult::Reply(response)) = pic.query_call(
+        backend_canister,
+        Principal::anonymous(),
+        "get_upgrade_history",
+        encode_one(()).unwrap(),
+    ) else {
+        panic!("Expected reply");
+    };
+    let history: Vec<UpgradeRecord> = decode_one(&response).unwrap();
+    assert_eq!(history.len(), 3);
+    assert!(matches!(history[0].arg, Arg::InitArg(_)));
+    assert_eq!(history[2].arg, arg);
+    assert!(history[1].canister_version < history[2].canister_version);
+}
+
+#[test]
+fn test_upgrade_from_v0_wasm() {
+    std::env::set_var("POCKET_IC_BIN", "/usr/local/bin/pocket-ic"); // Path of the pocket-ic binary
+    let pic = PocketIc::new();
+
+    let backend_canister = pic.create_canister();
+    pic.add_cycles(backend_canister, 2_000_000_000_000); // 2T Cycles
+    let wasm = fs::read(BACKEND_V0_WASM).expect("Wasm fixture not found");
+    let arg = Arg::InitArg(InitArg {
+        greeting: "Hello".to_string(),
+        ..Default::default()
+    });
+    pic.install_canister(backend_canister, wasm, encode_one(arg).unwrap(), None);
+
+    for name in ["ICP", "ICP", "Dfinity"] {
+        let Ok(WasmResult::Reply(_)) = pic.update_call(
+            backend_canister,
+            Principal::anonymous(),
+            "greet",
+            encode_one(name).unwrap(),
+        ) else {
+            panic!("Expected reply");
+        };
+    }
+
+    // the v0 canister did not persist its config, so the greeting has to be set
+    let wasm = fs::read(BACKEND_WASM).expect("Wasm file not found, run 'dfx build'.");
+    let arg = Arg::UpgradeArg(UpgradeArg {
+        greeting: Some("Hoi".to_string()),
+        ..Default::default()
+    });
+    pic.upgrade_canister(backend_canister, wasm, encode_one(arg).unwrap(), None)
+        .expect("Failed to upgrade canister");
+
+    let Ok(WasmResult::Reply(response)) = pic.query_call(
+        backend_canister,
+        Principal::anonymous(),
+        "greeted_name_count",
+        encode_one("ICP").unwrap(),
+    ) else {
+        panic!("Expected reply");
+    };
+    assert_eq!(decode_one::<u64>(&response).unwrap(), 2);
+
+    let Ok(WasmResult::Reply(response)) = pic.query_call(
+        backend_canister,
+        Principal::anonymous(),
+        "total_greeted_names_count",
+        encode_one(()).unwrap(),
+    ) else {
+        panic!("Expected reply");
+    };
+    assert_eq!(decode_one::<u64>(&response).unwrap(), 2);
+}
+
+#[test]
+fn test_validate_upgrade_arg() {
+    let (pic, backend_canister) = setup();
+
+    let validate = |arg: Arg| -> Result<InitArg, Vec<String>> {
+        let Ok(WasmResult::Reply(response)) = pic.query_call(
+            backend_canister,
+            Principal::anonymous(),
+            "validate_upgrade_arg",
+            encode_one(arg).unwrap(),
+        ) else {
+            panic!("Expected reply");
+        };
+        decode_one(&response).unwrap()
+    };
+
+    assert_eq!(
+        validate(Arg::UpgradeArg(UpgradeArg {
+            log_capacity: Some(10),
+            ..Default::default()
+        })),
+        Ok(InitArg {
+            greeting: "Hello".to_string(),
+            admins: Some(vec![]),
+            log_capacity: Some(10),
+            max_logs_body_size: Some(DEFAULT_MAX_LOGS_BODY_SIZE),
+            max_name_length: Some(DEFAULT_MAX_NAME_LENGTH),
+            max_log_message_size: Some(DEFAULT_MAX_LOG_MESSAGE_SIZE),
+            low_cycles_threshold_seconds: Some(DEFAULT_LOW_CYCLES_THRESHOLD_SECONDS),
+        })
+    );
+    assert_eq!(
+        validate(Arg::UpgradeArg(UpgradeArg {
+            greeting: Some(" ".to_string()),
+            max_name_length: Some(0),
+            ..Default::default()
+        })),
+        Err(vec![
+            "invalid greeting: greeting cannot be blank".to_string(),
+            "invalid max name length: max name length must be positive".to_string(),
+        ])
+    );
+}
+
+#[test]
+fn test_logs_survive_upgrade() {
+    let (pic, backend_canister) = setup();
+
+    let arg = Arg::UpgradeArg(UpgradeArg::default());
+    let wasm = fs::read(BACKEND_WASM).expect("Wasm file not found, run 'dfx build'.");
+    pic.upgrade_canister(backend_canister, wasm, encode_one(arg).unwrap(), None)
+        .expect("Failed to upgrade canister");
+
+    let request = HttpRequest {
+        method: "GET".to_string(),
+        url: "/logs?priority=info".to_string(),
+        headers: vec![],
+        body: Default::default(),
+    };
+    let Ok(WasmResult::Reply(response)) = pic.query_call(
+        backend_canister,
+        Principal::anonymous(),
+        "http_request",
+        encode_one(request).unwrap(),
+    ) else {
+        panic!("Expected reply");
+    };
+    let response: HttpResponse = decode_one(&response).unwrap();
+    let log: Log = serde_json::from_slice(&response.body).unwrap();
+    let messages: Vec<&str> = log
+        .entries
+        .iter()
+        .map(|entry| entry.message.as_str())
+        .collect();
+    assert!(messages[0].starts_with("[init]"));
+    assert!(messages
+        .iter()
+        .any(|message| message.starts_with("[upgrade]")));
+}
+
+#[test]
+fn test_get_logs() {
+    let (pic, backend_canister) = setup();
+
+    let get_logs = |filter: LogFilter| -> LogPage {
+        let Ok(WasmResult::Reply(response)) = pic.query_call(
+            backend_canister,
+            Principal::anonymous(),
+            "get_logs",
+            encode_one(filter).unwrap(),
+        ) else {
+            panic!("Expected reply");
+        };
+        decode_one(&response).unwrap()
+    };
+
+    let page = get_logs(LogFilter {
+        priority: Some(Priority::Info),
+        ..Default::default()
+    });
+    assert!(page.entries[0].message.starts_with("[init]"));
+    assert!(!page.truncated);
+    assert_eq!(page.next_cursor, None);
+
+    let page = get_logs(LogFilter {
+        contains: Some("[init]".to_string()),
+        sort: Some(Sort::Descending),
+        ..Default::default()
+    });
+    assert!(!page.entries.is_empty());
+    assert!(page
+        .entries
+        .iter()
+        .all(|entry| entry.message.contains("[init]")));
+
+    assert!(matches!(
+        pic.update_call(
+            backend_canister,
+            Principal::anonymous(),
+            "get_logs",
+            encode_one(LogFilter::default()).unwrap(),
+        ),
+        Ok(WasmResult::Reject(_))
+    ));
+}
+
+#[test]
+fn test_metrics_history() {
+    let (pic, backend_canister) = setup();
+    pic.tick();
+    pic.advance_time(Duration::from_secs(10 * 60));
+    pic.tick();
+
+    let history = |query: String| -> Vec<MetricsSample> {
+        let request = HttpRequest {
+            method: "GET".to_string(),
+            url: format!("/metrics/history?{}", query),
+            headers: vec![],
+            body: Default::default(),
+        };
+        let Ok(WasmResult::Reply(response)) = pic.query_call(
+            backend_canister,
+            Principal::anonymous(),
+            "http_request",
+            encode_one(request).unwrap(),
+        ) else {
+            panic!("Expected reply");
+        };
+        let response: HttpResponse = decode_one(&response).unwrap();
+        serde_json::from_slice(&response.body).unwrap()
+    };
+
+    let samples = history("since=0".to_string());
+    assert_eq!(samples.len(), 2);
+    assert!(samples[0].cycle_balance > 0);
+    assert!(samples[0].timestamp < samples[1].timestamp);
+    assert_eq!(
+        history(format!("since={}", samples[1].timestamp)),
+        vec![samples[1].clone()]
+    );
+    assert_eq!(history("limit=1".to_string()), vec![samples[0].clone()]);
+}
+
+#[test]
+fn test_get_metrics() {
+    let (pic, backend_canister) = setup();
+    for name in ["alice", "bob", "alice"] {
+        pic.update_call(
+            backend_canister,
+            Principal::anonymous(),
+            "greet",
+            encode_one(name).unwrap(),
+        )
+        .expect("Failed to greet");
+    }
+
+    let Ok(WasmResult::Reply(response)) = pic.query_call(
+        backend_canister,
+        Principal::anonymous(),
+        "get_metrics",
+        encode_one(()).unwrap(),
+    ) else {
+        panic!("Expected reply");
+    };
+    let metrics: Vec<MetricFamily> = decode_one(&response).unwrap();
+    let value = |name: &str| -> f64 {
+        let family = metrics
+            .iter()
+            .find(|family| family.name == name)
+            .unwrap_or_else(|| panic!("missing metric {}", name));
+        match family.samples[0].value {
+            SampleValue::Value(value) => value,
+            SampleValue::Histogram(_) => panic!("unexpected histogram {}", name),
+        }
+    };
+    assert_eq!(value("greetings_total"), 3.0);
+    assert_eq!(value("greeted_names"), 2.0);
+    assert!(value("cycle_balance") > 0.0);
+}
+
+#[test]
+fn test_method_rejections_are_counted() {
+    let (pic, backend_canister) = setup();
+    let long_name = "a".repeat(DEFAULT_MAX_NAME_LENGTH as usize + 1);
+    let Ok(WasmResult::Reject(reason)) = pic.update_call(
+        backend_canister,
+        Principal::anonymous(),
+        "greet",
+        encode_one(long_name).unwrap(),
+    ) else {
+        panic!("Expected reject");
+    };
+    assert!(reason.contains("name is too long"));
+    let Ok(WasmResult::Reject(reason)) = pic.update_call(
+        backend_canister,
+        Principal::anonymous(),
+        "total_greeted_names_count",
+        encode_one(()).unwrap(),
+    ) else {
+        panic!("Expected reject");
+    };
+    assert!(reason.contains("update call rejected"));
+
+    let Ok(WasmResult::Reply(response)) = pic.query_call(
+        backend_canister,
+        Principal::anonymous(),
+        "get_metrics",
+        encode_one(()).unwrap(),
+    ) else {
+        panic!("Expected reply");
+    };
+    let metrics: Vec<MetricFamily> = decode_one(&response).unwrap();
+    let rejections = metrics
+        .iter()
+        .find(|family| family.name == "canister_method_rejections_total")
+        .expect("missing metric canister_method_rejections_total");
+    for method in ["greet", "total_greeted_names_count"] {
+        let sample = rejections
+            .samples
+            .iter()
+            .find(|sample| sample.labels == vec![("method".to_string(), method.to_string())])
+            .unwrap_or_else(|| panic!("missing rejections of {}", method));
+        assert_eq!(sample.value, SampleValue::Value(1.0));
+    }
+}
+
+#[test]
+fn test_metrics_content_negotiation() {
+    let (pic, backend_canister) = setup();
+
+    let metrics = |url: &str, accept: Option<&str>| -> HttpResponse {
+        let request = HttpRequest {
+            method: "GET".to_string(),
+            url: url.to_string(),
+            headers: accept
+                .map(|accept| vec![("Accept".to_string(), accept.to_string())])
+                .unwrap_or_default(),
+            body: Default::default(),
+        };
+        let Ok(WasmResult::Reply(response)) = pic.query_call(
+            backend_canister,
+            Principal::anonymous(),
+            "http_request",
+            encode_one(request).unwrap(),
+        ) else {
+            panic!("Expected reply");
+        };
+        decode_one(&response).unwrap()
+    };
+    let content_type = |response: &HttpResponse| {
+        response
+            .headers
+            .iter()
+            .find(|(name, _)| name == "Content-Type")
+            .map(|(_, value)| value.clone())
+            .unwrap()
+    };
+
+    let response = metrics("/metrics", None);
+    assert_eq!(content_type(&response), "text/plain; version=0.0.4");
+
+    let response = metrics("/metrics", Some("application/openmetrics-text"));
+    assert!(content_type(&response).starts_with("application/openmetrics-text"));
+    assert!(String::from_utf8(response.body.to_vec())
+        .unwrap()
+        .ends_with("# EOF\n"));
+
+    let response = metrics("/metrics?format=json", Some("text/plain"));
+    assert!(content_type(&response).starts_with("application/json"));
+    let json: serde_json::Value = serde_json::from_slice(&response.body).unwrap();
+    assert_eq!(json["greetings_total"], 0.0);
+
+    assert_eq!(metrics("/metrics?format=xml", None).status_code, 400);
+}